@@ -0,0 +1,16 @@
+#!/usr/bin/env sh
+# Runs every bench script with the enum Value and the nan_boxing Value, the last line
+# each script prints is the elapsed time in milliseconds.
+set -e
+cd "$(dirname "$0")/.."
+
+cargo build --release -q
+cp target/release/clox target/release/clox-enum
+cargo build --release -q --features nan_boxing
+cp target/release/clox target/release/clox-nan
+
+for script in bench/*.lox; do
+    echo "== $script"
+    printf "enum:       %s ms\n" "$(target/release/clox-enum "$script" | tail -n 1)"
+    printf "nan_boxing: %s ms\n" "$(target/release/clox-nan "$script" | tail -n 1)"
+done
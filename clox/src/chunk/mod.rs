@@ -89,7 +89,7 @@ impl Chunk {
                 let val = &self.constants[cosntant as usize];
                 println!("{:-16} {:04} {}", "Closure", cosntant, val);
 
-                if let Value::Obj(Object::Fn(val)) = val {
+                if let Some(Object::Fn(val)) = val.as_obj().as_deref() {
                     for _ in 0..val.upvalue_count {
                         offset += 2;
                         let local = if self.code[offset - 2] == 1 {
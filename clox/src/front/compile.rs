@@ -11,17 +11,20 @@ struct Local {
     captured: bool,
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Default)]
 enum FunctionType {
     Fn,
+    #[default]
     Script,
 }
 
-impl Default for FunctionType {
-    fn default() -> Self {
-        Self::Script
-    }
-}
+const ASSIGNMENTS: [TokenType; 5] = [
+    TokenType::Equal,
+    TokenType::PlusEqual,
+    TokenType::MinusEqual,
+    TokenType::StarEqual,
+    TokenType::SlashEqual,
+];
 
 struct Upvalue {
     index: u8,
@@ -248,6 +251,99 @@ impl Compiler {
         self.end_scope();
     }
 
+    /// switch value { case a: ... case b: ... default: ... }
+    /// only the first matched case runs, there is no fallthrough
+    fn switch_statement(&mut self) {
+        // keep the switch value in a hidden local so every case can compare against it
+        self.begin_scope();
+        self.expression();
+        let slot = self.locals.len() as u8;
+        self.add_local(Token {
+            ty: TokenType::Switch,
+            str: Rc::new(String::new()),
+            line: self.previous().map_or(0, |t| t.line),
+        });
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after switch value.");
+
+        let mut end_jumps = vec![];
+        let mut case_jump = None;
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            match self.match_advances(&[TokenType::Case, TokenType::Default]) {
+                Some(Token {
+                    ty: TokenType::Case,
+                    ..
+                }) => {
+                    if has_default {
+                        self.error("Can't have a case after the default case.");
+                    }
+
+                    // the previous case didn't match, drop its comparison result
+                    if let Some(case_jump) = case_jump.take() {
+                        self.patch_jump(case_jump);
+                        self.emit_byte(OpCode::Pop);
+                    }
+
+                    self.emit_bytes(OpCode::GetLocal, slot);
+                    self.expression();
+                    self.consume(TokenType::Colon, "Expect ':' after case value.");
+                    self.emit_byte(OpCode::Equal);
+                    case_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+                    self.emit_byte(OpCode::Pop);
+
+                    self.case_body();
+                    end_jumps.push(self.emit_jump(OpCode::Jump));
+                }
+                Some(Token {
+                    ty: TokenType::Default,
+                    ..
+                }) => {
+                    if has_default {
+                        self.error("Can't have more than one default case.");
+                    }
+                    has_default = true;
+
+                    if let Some(case_jump) = case_jump.take() {
+                        self.patch_jump(case_jump);
+                        self.emit_byte(OpCode::Pop);
+                    }
+
+                    self.consume(TokenType::Colon, "Expect ':' after default.");
+                    self.case_body();
+                }
+                _ => {
+                    self.error_at_current("Expect 'case' or 'default' in switch body.");
+                    break;
+                }
+            }
+        }
+
+        if let Some(case_jump) = case_jump {
+            self.patch_jump(case_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after switch body.");
+        self.end_scope();
+    }
+
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
     fn var_declaration(&mut self) {
         let global = self.parse_variable("Expect variable name.");
 
@@ -297,12 +393,12 @@ impl Compiler {
         while self
             .current()
             .as_ref()
-            .map_or(false, |t| t.ty != TokenType::Eof)
+            .is_some_and(|t| t.ty != TokenType::Eof)
         {
             if self
                 .previous()
                 .as_ref()
-                .map_or(false, |t| t.ty == TokenType::Semicolon)
+                .is_some_and(|t| t.ty == TokenType::Semicolon)
             {
                 return;
             }
@@ -315,6 +411,7 @@ impl Compiler {
                     | TokenType::For
                     | TokenType::If
                     | TokenType::While
+                    | TokenType::Switch
                     | TokenType::Print
                     | TokenType::Return,
                 ) => return,
@@ -332,6 +429,7 @@ impl Compiler {
             TokenType::If,
             TokenType::While,
             TokenType::For,
+            TokenType::Switch,
             TokenType::Return,
         ]) {
             Some(Token {
@@ -356,6 +454,10 @@ impl Compiler {
             Some(Token {
                 ty: TokenType::For, ..
             }) => self.for_statement(),
+            Some(Token {
+                ty: TokenType::Switch,
+                ..
+            }) => self.switch_statement(),
             Some(Token {
                 ty: TokenType::Return,
                 ..
@@ -619,11 +721,29 @@ impl Compiler {
             }
         };
 
-        if can_assign && self.match_advance(TokenType::Equal).is_some() {
-            self.expression();
-            self.emit_bytes(set_op, arg);
-        } else {
-            self.emit_bytes(get_op, arg)
+        if !can_assign {
+            self.emit_bytes(get_op, arg);
+            return;
+        }
+
+        match self.match_advances(&ASSIGNMENTS).map(|t| t.ty) {
+            Some(TokenType::Equal) => {
+                self.expression();
+                self.emit_bytes(set_op, arg);
+            }
+            Some(ty) => {
+                // a op= b is compiled as a = a op b
+                self.emit_bytes(get_op, arg);
+                self.expression();
+                match ty {
+                    TokenType::PlusEqual => self.emit_byte(OpCode::Add),
+                    TokenType::MinusEqual => self.emit_byte(OpCode::Subtract),
+                    TokenType::StarEqual => self.emit_byte(OpCode::Multiply),
+                    _ => self.emit_byte(OpCode::Divide),
+                }
+                self.emit_bytes(set_op, arg);
+            }
+            None => self.emit_bytes(get_op, arg),
         }
     }
 
@@ -631,7 +751,7 @@ impl Compiler {
         if let Some(enclsoing) = self.enclosing.as_mut() {
             let local = enclsoing.resolve_local(name);
             if local != -1 {
-                self.locals[local as usize].captured = true;
+                enclsoing.locals[local as usize].captured = true;
                 return self.add_upvalue(local as u8, true);
             }
 
@@ -677,7 +797,7 @@ impl Compiler {
             while self
                 .current()
                 .as_ref()
-                .map_or(false, |t| precedence <= get_rule(t.ty).precedence)
+                .is_some_and(|t| precedence <= get_rule(t.ty).precedence)
             {
                 self.advance();
                 if let Some(rule) = self.previous().map(|t| get_rule(t.ty)) {
@@ -685,7 +805,7 @@ impl Compiler {
                     infix(self, can_assign);
                 }
 
-                if can_assign && self.match_advance(TokenType::Equal).is_some() {
+                if can_assign && self.match_advances(&ASSIGNMENTS).is_some() {
                     self.error("Invalid assignment target.");
                 }
             }
@@ -764,6 +884,24 @@ impl Compiler {
         self.patch_jump(end_jump);
     }
 
+    pub fn conditional(&mut self, _: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.expression();
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional operator.",
+        );
+
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop);
+        // right associative, a ? b : c ? d : e is a ? b : (c ? d : e)
+        self.parse_precedence(Precedence::Conditional);
+
+        self.patch_jump(end_jump);
+    }
+
     pub fn or(&mut self, _: bool) {
         let end_jump = self.emit_jump(OpCode::JumpIfTrue);
         self.emit_byte(OpCode::Pop);
@@ -830,7 +968,7 @@ impl Compiler {
     }
 
     fn check(&self, ty: TokenType) -> bool {
-        self.current().as_ref().map_or(false, |t| t.ty == ty)
+        self.current().as_ref().is_some_and(|t| t.ty == ty)
     }
 
     fn current(&self) -> Option<&Token> {
@@ -5,15 +5,16 @@ pub type ParseFn = fn(&mut Compiler, bool);
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precedence {
     None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // = += -= *= /=
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! -
+    Call,        // . ()
     Primary,
 }
 
@@ -27,7 +28,8 @@ impl Precedence {
     pub fn heigher(&self) -> Self {
         match self {
             Precedence::None => Self::Assignment,
-            Precedence::Assignment => Self::Or,
+            Precedence::Assignment => Self::Conditional,
+            Precedence::Conditional => Self::Or,
             Precedence::Or => Self::And,
             Precedence::And => Self::Equality,
             Precedence::Equality => Self::Comparison,
@@ -139,6 +141,11 @@ pub fn get_rule(ty: TokenType) -> ParseRule {
         infix: Compiler::or,
         precedence: Precedence::And,
     };
+    const QUESTION: ParseRule = ParseRule {
+        prefix: nothing,
+        infix: Compiler::conditional,
+        precedence: Precedence::Conditional,
+    };
     const NONE: ParseRule = ParseRule {
         prefix: nothing,
         infix: nothing,
@@ -165,6 +172,7 @@ pub fn get_rule(ty: TokenType) -> ParseRule {
         TokenType::String => STRING,
         TokenType::And => AND,
         TokenType::Or => OR,
+        TokenType::Question => QUESTION,
         _ => NONE,
     }
 }
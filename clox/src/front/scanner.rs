@@ -34,11 +34,41 @@ impl Scanner {
             '}' => self.make_token(TokenType::RightBrace),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
             ';' => self.make_token(TokenType::Semicolon),
-            '*' => self.make_token(TokenType::Star),
-            '/' => self.make_token(TokenType::Slash),
+            '?' => self.make_token(TokenType::Question),
+            ':' => self.make_token(TokenType::Colon),
+            '-' => {
+                let toekn = if self.advance_if_match('=') {
+                    TokenType::MinusEqual
+                } else {
+                    TokenType::Minus
+                };
+                self.make_token(toekn)
+            }
+            '+' => {
+                let toekn = if self.advance_if_match('=') {
+                    TokenType::PlusEqual
+                } else {
+                    TokenType::Plus
+                };
+                self.make_token(toekn)
+            }
+            '*' => {
+                let toekn = if self.advance_if_match('=') {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                };
+                self.make_token(toekn)
+            }
+            '/' => {
+                let toekn = if self.advance_if_match('=') {
+                    TokenType::SlashEqual
+                } else {
+                    TokenType::Slash
+                };
+                self.make_token(toekn)
+            }
             '!' => {
                 let toekn = if self.advance_if_match('=') {
                     TokenType::BangEqual
@@ -89,13 +119,9 @@ impl Scanner {
                     self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -165,7 +191,7 @@ impl Scanner {
     }
 
     fn is_at_end(&self) -> bool {
-        self.source.get(self.current).map_or(true, |c| *c == '\0')
+        self.source.get(self.current).is_none_or(|c| *c == '\0')
     }
 
     fn peek(&self) -> char {
@@ -238,6 +264,9 @@ fn to_keyword(str: &str) -> TokenType {
         "var" => TokenType::Var,
         "while" => TokenType::While,
         "break" => TokenType::Break,
+        "switch" => TokenType::Switch,
+        "case" => TokenType::Case,
+        "default" => TokenType::Default,
         _ => TokenType::Identifier,
     }
 }
@@ -27,6 +27,8 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Question,
+    Colon,
 
     // One or two character tokens.
     Bang,
@@ -37,6 +39,10 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
 
     // Literals.
     Identifier,
@@ -61,6 +67,9 @@ pub enum TokenType {
     Var,
     While,
     Break,
+    Switch,
+    Case,
+    Default,
 
     Eof,
     Error,
@@ -5,4 +5,4 @@ mod vm;
 
 use chunk::{Chunk, OpCode};
 use value::Value;
-pub use vm::{interpret, InterpretResult, Vm};
+pub use vm::{interpret, FrameInfo, InterpretResult, RuntimeError, Vm};
@@ -29,7 +29,9 @@ fn repl(vm: &mut Vm) {
         let _ = io::stdout().flush();
         if let Ok(size) = stdio.read_line(&mut string) {
             if 0 < size {
-                interpret(&string, vm);
+                if let InterpretResult::RuntimeError(err) = interpret(&string, vm) {
+                    eprint!("{}", err);
+                }
             }
         }
         string.clear();
@@ -45,7 +47,10 @@ fn run_file(vm: &mut Vm, file: &str) {
             match result {
                 InterpretResult::Ok => (),
                 InterpretResult::CompileError => exit(65),
-                InterpretResult::RuntimeError => exit(70),
+                InterpretResult::RuntimeError(err) => {
+                    eprint!("{}", err);
+                    exit(70)
+                }
                 InterpretResult::NativeFunctionError(_) => exit(70),
             }
         }
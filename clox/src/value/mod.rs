@@ -1,10 +1,14 @@
+#[cfg(feature = "nan_boxing")]
+mod nan;
 mod object;
 
-use std::fmt::Debug;
 use std::{fmt::Display, rc::Rc};
 
+#[cfg(feature = "nan_boxing")]
+pub use self::nan::Value;
 pub use self::object::{Closure, Function, NativeFn, NativeFunction, Object, UpValue};
 
+#[cfg(not(feature = "nan_boxing"))]
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Nil,
@@ -13,71 +17,141 @@ pub enum Value {
     Obj(Object),
 }
 
-impl Eq for Value {
-    fn assert_receiver_is_total_eq(&self) {}
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+    pub fn nil() -> Self {
+        Value::Nil
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(bool) => Some(*bool),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef<'_>> {
+        match self {
+            Value::Obj(obj) => Some(ObjRef(obj)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&String> {
+        match self {
+            Value::Obj(Object::Str(str)) => Some(str),
+            _ => None,
+        }
+    }
+}
+
+/// An object borrowed from a value
+#[cfg(not(feature = "nan_boxing"))]
+pub struct ObjRef<'a>(&'a Object);
+
+#[cfg(not(feature = "nan_boxing"))]
+impl std::ops::Deref for ObjRef<'_> {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        self.0
+    }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
 impl From<f64> for Value {
     fn from(val: f64) -> Self {
         Value::Number(val)
     }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
 impl From<bool> for Value {
     fn from(val: bool) -> Self {
         Value::Bool(val)
     }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
+impl From<Object> for Value {
+    fn from(obj: Object) -> Self {
+        Value::Obj(obj)
+    }
+}
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || self.as_bool() == Some(false)
+    }
+}
+
+impl Eq for Value {}
+
 impl From<&str> for Value {
     fn from(val: &str) -> Self {
-        Value::Obj(Object::Str(Rc::new(val.to_string())))
+        Object::Str(Rc::new(val.to_string())).into()
     }
 }
 
 impl From<String> for Value {
     fn from(val: String) -> Self {
-        Value::Obj(Object::Str(Rc::new(val)))
+        Object::Str(Rc::new(val)).into()
     }
 }
 
 impl From<Rc<String>> for Value {
     fn from(val: Rc<String>) -> Self {
-        Value::Obj(Object::Str(val))
+        Object::Str(val).into()
     }
 }
 
 impl From<Function> for Value {
     fn from(function: Function) -> Self {
-        Value::Obj(Object::Fn(Rc::new(function)))
+        Object::Fn(Rc::new(function)).into()
     }
 }
 
 impl From<Closure> for Value {
     fn from(cl: Closure) -> Self {
-        Value::Obj(Object::Closure(cl))
+        Object::Closure(Rc::new(cl)).into()
     }
 }
 
 impl From<Rc<Function>> for Value {
     fn from(function: Rc<Function>) -> Self {
-        Value::Obj(Object::Fn(function))
+        Object::Fn(function).into()
     }
 }
 
 impl From<NativeFunction> for Value {
     fn from(function: NativeFunction) -> Self {
-        Value::Obj(Object::NativeFn(Rc::new(function)))
+        Object::NativeFn(Rc::new(function)).into()
     }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Bool(bool) => write!(f, "{}", bool),
-            Value::Number(num) => write!(f, "{}", num),
-            Value::Obj(a) => std::fmt::Display::fmt(&a, f),
+        if self.is_nil() {
+            write!(f, "nil")
+        } else if let Some(bool) = self.as_bool() {
+            write!(f, "{}", bool)
+        } else if let Some(num) = self.as_number() {
+            write!(f, "{}", num)
+        } else if let Some(obj) = self.as_obj() {
+            std::fmt::Display::fmt(&*obj, f)
+        } else {
+            unreachable!("value is neither nil, bool, number nor object")
         }
     }
 }
@@ -0,0 +1,301 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use super::{Closure, Function, NativeFunction, Object};
+
+// A value is a single u64. Numbers are stored as their raw f64 bits, every other value
+// hides inside the unused payload of a quiet NaN:
+//
+//   nil/bool:  0 11111111111 11 00 0000000000000000000000000000000000000000000000 tt
+//   upvalue:   0 11111111111 11 01 iiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiiii
+//   object:    1 11111111111 11 kk pppppppppppppppppppppppppppppppppppppppppppppppp
+//
+// where `tt` is the tag, `i` the slot of the upvalue, `kk` the kind of object and `p`
+// the 48 bit address of the `Rc` the object variant holds, so there is no box around it.
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const KIND_SHIFT: u32 = 48;
+const PAYLOAD: u64 = (1 << KIND_SHIFT) - 1;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL_VAL: u64 = QNAN | TAG_NIL;
+const FALSE_VAL: u64 = QNAN | TAG_FALSE;
+const TRUE_VAL: u64 = QNAN | TAG_TRUE;
+const UPVALUE: u64 = QNAN | 1 << KIND_SHIFT;
+
+const KIND_STR: u64 = 0;
+const KIND_FN: u64 = 1;
+const KIND_NATIVE_FN: u64 = 2;
+const KIND_CLOSURE: u64 = 3;
+
+/// The marker keeps it `!Send` and `!Sync` like the enum, the refcounts of the `Rc`s
+/// behind the pointers aren't atomic
+pub struct Value(u64, PhantomData<Rc<Object>>);
+
+// fails to compile if `Value` is ever `Send` or `Sync`, one impl would be ambiguous
+const _: fn() = || {
+    trait AmbiguousIfSendOrSync<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSendOrSync<()> for T {}
+    impl<T: ?Sized + Send> AmbiguousIfSendOrSync<u8> for T {}
+    impl<T: ?Sized + Sync> AmbiguousIfSendOrSync<u16> for T {}
+    let _ = <Value as AmbiguousIfSendOrSync<_>>::check;
+};
+
+/// An object borrowed from a value. It holds the value's own `Rc`s and never
+/// drops them, the value keeps the strong count
+pub struct ObjRef<'a> {
+    obj: ManuallyDrop<Object>,
+    _value: PhantomData<&'a Value>,
+}
+
+impl Deref for ObjRef<'_> {
+    type Target = Object;
+
+    fn deref(&self) -> &Object {
+        &self.obj
+    }
+}
+
+impl Value {
+    pub fn nil() -> Self {
+        Self::from_bits(NIL_VAL)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL_VAL
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            TRUE_VAL => Some(true),
+            FALSE_VAL => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.is_number() {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef<'_>> {
+        let obj = if self.is_obj() {
+            // SAFETY: the pointer came from `Rc::into_raw` for its kind, the `Rc` made
+            // of it is never dropped and this value holds it alive while borrowed
+            unsafe {
+                match self.kind() {
+                    KIND_STR => Object::Str(Rc::from_raw(self.ptr())),
+                    KIND_FN => Object::Fn(Rc::from_raw(self.ptr())),
+                    KIND_NATIVE_FN => Object::NativeFn(Rc::from_raw(self.ptr())),
+                    _ => Object::Closure(Rc::from_raw(self.ptr())),
+                }
+            }
+        } else if self.0 & !PAYLOAD == UPVALUE {
+            Object::UpValue((self.0 & PAYLOAD) as usize)
+        } else {
+            return None;
+        };
+        Some(ObjRef {
+            obj: ManuallyDrop::new(obj),
+            _value: PhantomData,
+        })
+    }
+
+    pub fn as_str(&self) -> Option<&String> {
+        // SAFETY: the string lives as long as this value holds its strong count
+        (self.is_obj() && self.kind() == KIND_STR).then(|| unsafe { &*self.ptr::<String>() })
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        Self(bits, PhantomData)
+    }
+
+    fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    fn is_obj(&self) -> bool {
+        self.0 & (QNAN | SIGN_BIT) == (QNAN | SIGN_BIT)
+    }
+
+    fn kind(&self) -> u64 {
+        self.0 >> KIND_SHIFT & 0b11
+    }
+
+    fn ptr<T>(&self) -> *const T {
+        (self.0 & PAYLOAD) as *const T
+    }
+
+    fn obj<T>(kind: u64, rc: Rc<T>) -> Self {
+        let ptr = Rc::into_raw(rc) as u64;
+        debug_assert!(ptr & !PAYLOAD == 0, "pointer wider than 48 bits");
+        Self::from_bits(SIGN_BIT | QNAN | kind << KIND_SHIFT | ptr)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        // every NaN collapses to the canonical one, so no number can look like a tagged value
+        if val.is_nan() {
+            Self::from_bits(f64::NAN.to_bits())
+        } else {
+            Self::from_bits(val.to_bits())
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        Self::from_bits(if val { TRUE_VAL } else { FALSE_VAL })
+    }
+}
+
+impl From<Object> for Value {
+    fn from(obj: Object) -> Self {
+        match obj {
+            Object::Str(str) => Self::obj(KIND_STR, str),
+            Object::Fn(fun) => Self::obj(KIND_FN, fun),
+            Object::NativeFn(fun) => Self::obj(KIND_NATIVE_FN, fun),
+            Object::Closure(cl) => Self::obj(KIND_CLOSURE, cl),
+            Object::UpValue(slot) => {
+                debug_assert!(
+                    slot as u64 & !PAYLOAD == 0,
+                    "upvalue slot wider than 48 bits"
+                );
+                Self::from_bits(UPVALUE | slot as u64)
+            }
+        }
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if self.is_obj() {
+            // SAFETY: the pointer came from `Rc::into_raw` for its kind, the new value
+            // takes its own strong count
+            unsafe {
+                match self.kind() {
+                    KIND_STR => Rc::increment_strong_count(self.ptr::<String>()),
+                    KIND_FN => Rc::increment_strong_count(self.ptr::<Function>()),
+                    KIND_NATIVE_FN => Rc::increment_strong_count(self.ptr::<NativeFunction>()),
+                    _ => Rc::increment_strong_count(self.ptr::<Closure>()),
+                }
+            }
+        }
+        Self::from_bits(self.0)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if self.is_obj() {
+            // SAFETY: releases the strong count taken in `from` or `clone`
+            unsafe {
+                match self.kind() {
+                    KIND_STR => Rc::decrement_strong_count(self.ptr::<String>()),
+                    KIND_FN => Rc::decrement_strong_count(self.ptr::<Function>()),
+                    KIND_NATIVE_FN => Rc::decrement_strong_count(self.ptr::<NativeFunction>()),
+                    _ => Rc::decrement_strong_count(self.ptr::<Closure>()),
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (self.as_number(), other.as_number()) {
+            return a == b;
+        }
+
+        match (self.as_obj(), other.as_obj()) {
+            (Some(a), Some(b)) => *a == *b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(num) = self.as_number() {
+            f.debug_tuple("Number").field(&num).finish()
+        } else if let Some(bool) = self.as_bool() {
+            f.debug_tuple("Bool").field(&bool).finish()
+        } else if let Some(obj) = self.as_obj() {
+            f.debug_tuple("Obj").field(&*obj).finish()
+        } else {
+            write!(f, "Nil")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clone_and_drop_keep_the_strong_count() {
+        let str = Rc::new(String::from("lox"));
+        let val = Value::from(Object::Str(str.clone()));
+        assert_eq!(Rc::strong_count(&str), 2);
+
+        let copy = val.clone();
+        assert_eq!(Rc::strong_count(&str), 3);
+        // borrowing doesn't touch the count
+        if let Some(Object::Str(borrowed)) = copy.as_obj().as_deref() {
+            assert!(Rc::ptr_eq(borrowed, &str));
+            assert_eq!(Rc::strong_count(&str), 3);
+        } else {
+            panic!("not a string: {:?}", copy);
+        }
+        assert_eq!(copy.as_str(), Some(str.as_ref()));
+        assert_eq!(Rc::strong_count(&str), 3);
+
+        drop(copy);
+        drop(val);
+        assert_eq!(Rc::strong_count(&str), 1);
+    }
+
+    #[test]
+    fn closure_is_freed_with_its_last_value() {
+        let function = Rc::new(Function::default());
+        let val = Value::from(Closure::new(function.clone()));
+        let copy = val.clone();
+        assert_eq!(Rc::strong_count(&function), 2);
+
+        drop(val);
+        assert!(matches!(copy.as_obj().as_deref(), Some(Object::Closure(_))));
+        drop(copy);
+        assert_eq!(Rc::strong_count(&function), 1);
+    }
+
+    #[test]
+    fn nan_is_canonicalised() {
+        // payloads that would read back as an object, true and an upvalue
+        for bits in [SIGN_BIT | QNAN | 0x1234, TRUE_VAL, UPVALUE | 7] {
+            let val = Value::from(f64::from_bits(bits));
+            assert!(val.as_number().is_some_and(f64::is_nan), "{:#x}", bits);
+            assert!(val.as_obj().is_none());
+            assert_eq!(val.as_bool(), None);
+            assert!(!val.is_nil());
+        }
+    }
+
+    #[test]
+    fn upvalue_round_trips() {
+        let val = Value::from(Object::UpValue(42));
+        assert_eq!(val.as_obj().as_deref(), Some(&Object::UpValue(42)));
+        assert_eq!(val.as_number(), None);
+    }
+}
@@ -10,7 +10,7 @@ pub enum Object {
     Str(Rc<String>),
     Fn(Rc<Function>),
     NativeFn(Rc<NativeFunction>),
-    Closure(Closure),
+    Closure(Rc<Closure>),
     UpValue(usize),
 }
 
@@ -110,7 +110,10 @@ pub struct UpValue {
 impl UpValue {
     pub fn new(location: usize) -> Self {
         Self {
-            innner: RefCell::new(UpValueInner { location }),
+            innner: RefCell::new(UpValueInner {
+                location,
+                closed: None,
+            }),
         }
     }
 
@@ -124,6 +127,16 @@ impl UpValue {
         }
     }
 
+    /// The value once it's moved off the stack, `None` while it's still open
+    pub fn closed(&self) -> Option<Value> {
+        self.innner.borrow().closed.clone()
+    }
+
+    /// Move the value off the stack, or replace it once it's closed
+    pub fn close(&self, value: Value) {
+        self.innner.borrow_mut().closed = Some(value);
+    }
+
     pub fn set_location(&self, location: usize) -> Result<(), BorrowMutError> {
         match self.innner.try_borrow_mut() {
             Ok(mut inner) => {
@@ -139,4 +152,5 @@ impl UpValue {
 #[derive(Debug, PartialEq, Clone)]
 struct UpValueInner {
     location: usize,
+    closed: Option<Value>,
 }
@@ -1,4 +1,7 @@
+use std::cell::RefCell;
 use std::collections::{HashMap, LinkedList, VecDeque};
+use std::fmt::Display;
+use std::io::{self, Write};
 use std::ops::{Div, Mul, Sub};
 use std::rc::Rc;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -12,13 +15,13 @@ use crate::{
 
 #[derive(Default)]
 struct CallFrame {
-    closure: Closure,
+    closure: Rc<Closure>,
     ip: usize,
     slot_idx: usize,
 }
 
 impl CallFrame {
-    fn new(closure: Closure) -> Self {
+    fn new(closure: Rc<Closure>) -> Self {
         Self {
             closure,
             ip: 0,
@@ -46,14 +49,51 @@ impl CallFrame {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Debug)]
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
+    RuntimeError(RuntimeError),
     NativeFunctionError(String),
 }
 
+/// A runtime error with the call stack at the moment it was raised
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    /// source line of the instruction that failed
+    pub line: u32,
+    /// innermost frame first, the script frame last
+    pub frames: Vec<FrameInfo>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FrameInfo {
+    /// empty for the top-level script
+    pub function: String,
+    pub line: u32,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        for frame in &self.frames {
+            writeln!(f, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for FrameInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "[line {}] in script", self.line)
+        } else {
+            write!(f, "[line {}] in {}()", self.line, self.function)
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Vm {
     stack: VecDeque<Value>,
@@ -61,6 +101,8 @@ pub struct Vm {
     cur_frame: CallFrame,
     globals: HashMap<Rc<String>, Value>,
     upvalus: LinkedList<Rc<UpValue>>,
+    /// where `print` writes to, stdout if not set
+    output: Option<Rc<RefCell<dyn Write>>>,
 }
 
 impl Vm {
@@ -71,11 +113,18 @@ impl Vm {
             globals: HashMap::new(),
             cur_frame: CallFrame::default(),
             upvalus: LinkedList::new(),
+            output: None,
         }
     }
 
+    /// Redirect the output of `print` statements, e.g. to a buffer the host keeps a
+    /// handle to and reads after a run
+    pub fn set_output(&mut self, output: Rc<RefCell<dyn Write>>) {
+        self.output = Some(output);
+    }
+
     pub fn run(&mut self, function: Function) -> InterpretResult {
-        let closure = Closure::new(Rc::new(function));
+        let closure = Rc::new(Closure::new(Rc::new(function)));
         self.push(Closure::new(closure.function.clone()));
         match self.call_fun(closure, 0) {
             Ok(frame) => self.cur_frame = frame,
@@ -105,6 +154,7 @@ impl Vm {
                     Some(val) => match self.frames.pop_back() {
                         Some(frame) => {
                             let slot_idx = self.cur_frame.slot_idx;
+                            self.close_upvalues(slot_idx);
                             self.stack.drain(slot_idx..);
                             self.push(val);
                             self.cur_frame = frame;
@@ -115,8 +165,7 @@ impl Vm {
                         }
                     },
                     None => {
-                        self.runtime_error("method return, stack too short");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("method return, stack too short");
                     }
                 },
                 OpCode::Constant => {
@@ -124,35 +173,35 @@ impl Vm {
                     self.push(constant);
                 }
                 OpCode::Negate => {
-                    if let Some(Value::Number(val)) = self.pop() {
+                    if let Some(val) = self.pop().and_then(|v| v.as_number()) {
                         self.push(-val);
                     } else {
-                        self.runtime_error("Operand must be a number");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("Operand must be a number");
                     }
                 }
-                OpCode::Add => match (self.peak(0), self.peak(1)) {
-                    (Some(Value::Number(b)), Some(Value::Number(a))) => {
-                        let res = a + b;
+                OpCode::Add => {
+                    let (b, a) = (self.peak(0), self.peak(1));
+                    if let (Some(b), Some(a)) =
+                        (b.and_then(|v| v.as_number()), a.and_then(|v| v.as_number()))
+                    {
                         self.pop();
                         self.pop();
-                        self.push(res)
-                    }
-                    (Some(Value::Obj(Object::Str(b))), Some(Value::Obj(Object::Str(a)))) => {
+                        self.push(a + b)
+                    } else if let (Some(b), Some(a)) =
+                        (b.and_then(|v| v.as_str()), a.and_then(|v| v.as_str()))
+                    {
                         let new_str = {
-                            let mut a = a.as_ref().clone();
+                            let mut a = a.clone();
                             a.push_str(b);
                             a
                         };
                         self.pop();
                         self.pop();
                         self.push(new_str)
+                    } else {
+                        return self.runtime_error("Operands must be two numbers or two strings.");
                     }
-                    _ => {
-                        self.runtime_error("Operands must be two numbers or two strings.");
-                        return InterpretResult::RuntimeError;
-                    }
-                },
+                }
                 OpCode::Subtract => {
                     let res = self.binary_op(f64::sub);
                     if res != InterpretResult::Ok {
@@ -183,15 +232,14 @@ impl Vm {
                         return res;
                     }
                 }
-                OpCode::Nil => self.push(Value::Nil),
+                OpCode::Nil => self.push(Value::nil()),
                 OpCode::True => self.push(true),
                 OpCode::False => self.push(false),
                 OpCode::Equal => {
                     if let (Some(b), Some(a)) = (self.pop(), self.pop()) {
                         self.push(a == b);
                     } else {
-                        self.runtime_error("equal must have two operands");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("equal must have two operands");
                     }
                 }
                 OpCode::Bang => {
@@ -200,55 +248,54 @@ impl Vm {
                 }
                 OpCode::Print => {
                     if let Some(val) = self.pop() {
-                        println!("{}", val);
+                        let res = match &self.output {
+                            Some(output) => writeln!(output.borrow_mut(), "{}", val),
+                            None => writeln!(io::stdout(), "{}", val),
+                        };
+                        if let Err(err) = res {
+                            return self.runtime_error(&format!("print error, {}", err));
+                        }
                     }
                 }
                 OpCode::Pop => {
                     if self.pop().is_none() {
-                        self.runtime_error("Stack to too short");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("Stack to too short");
                     }
                 }
                 OpCode::DefineGlobal => {
-                    if let Value::Obj(Object::Str(name)) = self.read_consnt() {
+                    if let Some(Object::Str(name)) = self.read_consnt().as_obj().as_deref() {
                         if let Some(value) = self.pop() {
                             self.globals.insert(name.clone(), value);
                         } else {
-                            self.runtime_error(&format!(
+                            return self.runtime_error(&format!(
                                 "value not exists, define global:{} error",
                                 name
                             ));
-                            return InterpretResult::RuntimeError;
                         }
                     } else {
-                        self.runtime_error("variable name must be a string");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("variable name must be a string");
                     }
                 }
                 OpCode::GetGlobal => {
-                    if let Value::Obj(Object::Str(name)) = self.read_consnt() {
-                        if let Some(value) = self.globals.get(&name) {
+                    if let Some(Object::Str(name)) = self.read_consnt().as_obj().as_deref() {
+                        if let Some(value) = self.globals.get(name) {
                             self.push(value.clone())
                         } else {
-                            self.runtime_error(&format!("Undefined varaible {}", name));
-                            return InterpretResult::RuntimeError;
+                            return self.runtime_error(&format!("Undefined varaible {}", name));
                         }
                     } else {
-                        self.runtime_error("variable name must be a string");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("variable name must be a string");
                     }
                 }
                 OpCode::SetGlobal => {
-                    if let Value::Obj(Object::Str(name)) = self.read_consnt() {
-                        if !self.globals.contains_key(&name) {
-                            self.runtime_error(&format!("Undefined varaible {}", name));
-                            return InterpretResult::RuntimeError;
+                    if let Some(Object::Str(name)) = self.read_consnt().as_obj().as_deref() {
+                        if !self.globals.contains_key(name) {
+                            return self.runtime_error(&format!("Undefined varaible {}", name));
                         }
-                        let val = self.peak(0).unwrap_or(&Value::Nil);
-                        self.globals.insert(name, val.clone());
+                        let val = self.peak(0).cloned().unwrap_or_else(Value::nil);
+                        self.globals.insert(name.clone(), val);
                     } else {
-                        self.runtime_error("variable name must be a string");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("variable name must be a string");
                     }
                 }
                 OpCode::GetLocal => {
@@ -256,8 +303,8 @@ impl Vm {
                     if let Some(val) = self.stack.get(self.cur_frame.slot_idx + slot as usize) {
                         self.push(val.clone())
                     } else {
-                        self.runtime_error(&format!("getLocal operand error, slot:{}", slot));
-                        return InterpretResult::RuntimeError;
+                        return self
+                            .runtime_error(&format!("getLocal operand error, slot:{}", slot));
                     }
                 }
                 OpCode::SetLocal => {
@@ -265,16 +312,14 @@ impl Vm {
                     let val = if let Some(val) = self.peak(0) {
                         val.clone()
                     } else {
-                        self.runtime_error("setLocal no operand");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("setLocal no operand");
                     };
 
                     if let Some(local) = self.stack.get_mut(self.cur_frame.slot_idx + slot as usize)
                     {
                         *local = val.clone();
                     } else {
-                        self.runtime_error("setLocal target not exits");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("setLocal target not exits");
                     }
                 }
                 OpCode::JumpIfFalse => {
@@ -305,8 +350,8 @@ impl Vm {
                     }
                 }
                 OpCode::Closure => {
-                    if let Value::Obj(Object::Fn(function)) = self.read_consnt() {
-                        let mut closure = Closure::new(function);
+                    if let Some(Object::Fn(function)) = self.read_consnt().as_obj().as_deref() {
+                        let mut closure = Closure::new(function.clone());
                         for _ in 0..closure.function.upvalue_count {
                             let is_local = self.read_byte() == 1;
                             let index = self.read_byte() as usize;
@@ -323,61 +368,83 @@ impl Vm {
                         }
                         self.push(closure);
                     } else {
-                        self.runtime_error("can' only create closure from function");
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("can' only create closure from function");
                     }
                 }
                 OpCode::GetUpValue => {
                     let slot = self.read_byte() as usize;
-                    let slot = self.cur_frame.closure.upvalues[slot].location();
-                    if let Some(val) = self.stack.get(slot) {
-                        self.push(val.clone())
+                    let upvalue = &self.cur_frame.closure.upvalues[slot];
+                    let val = match upvalue.closed() {
+                        Some(val) => Some(val),
+                        None => self.stack.get(upvalue.location()).cloned(),
+                    };
+                    if let Some(val) = val {
+                        self.push(val)
                     } else {
-                        self.runtime_error(&format!("GetUpValue operand error, slot:{}", slot));
-                        return InterpretResult::RuntimeError;
+                        return self
+                            .runtime_error(&format!("GetUpValue operand error, slot:{}", slot));
                     }
                 }
                 OpCode::SetUpValue => {
-                    let idx = self.stack.len() - 1;
                     let slot = self.read_byte() as usize;
-                    if let Err(err) = self.cur_frame.closure.upvalues[slot].set_location(idx) {
-                        self.runtime_error(&format!(
-                            "SetUpValue operand error, slot:{}, data race:{}",
-                            slot, err
-                        ));
-                        return InterpretResult::RuntimeError;
+                    let upvalue = self.cur_frame.closure.upvalues[slot].clone();
+                    let val = self.peak(0).cloned().unwrap_or_else(Value::nil);
+                    if upvalue.closed().is_some() {
+                        upvalue.close(val);
+                    } else if let Some(upvalue) = self.stack.get_mut(upvalue.location()) {
+                        *upvalue = val;
+                    } else {
+                        return self
+                            .runtime_error(&format!("SetUpValue operand error, slot:{}", slot));
                     }
                 }
                 OpCode::Unknown(a) => {
-                    eprintln!("Unknow opcode ip:{:?}, byte:{:?}", self.cur_frame.ip, a);
-                    return InterpretResult::RuntimeError;
+                    return self.runtime_error(&format!(
+                        "Unknow opcode ip:{:?}, byte:{:?}",
+                        self.cur_frame.ip, a
+                    ));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop();
                 }
-                OpCode::CloseUpvalue => todo!(),
             }
         }
     }
 
     fn capture_upvalue(&mut self, location: usize) -> Rc<UpValue> {
-        let mut upvalue = None;
-
-        for val in self.upvalus.iter() {
-            if val.location() <= location {
-                upvalue = Some(val.clone());
-                break;
-            }
+        // closures capturing the same variable share its upvalue
+        if let Some(upvalue) = self.upvalus.iter().find(|val| val.location() == location) {
+            return upvalue.clone();
         }
+        let upvalue = Rc::new(UpValue::new(location));
+        self.upvalus.push_front(upvalue.clone());
+        upvalue
+    }
 
-        if let Some(upvalue) = upvalue {
-            if upvalue.location() == location {
-                return upvalue;
-            }
+    /// Move the values of the open upvalues at `last` and above off the stack,
+    /// before their slots are popped
+    fn close_upvalues(&mut self, last: usize) {
+        let (closing, open): (LinkedList<_>, _) = std::mem::take(&mut self.upvalus)
+            .into_iter()
+            .partition(|val| val.location() >= last);
+        for upvalue in closing {
+            let val = self
+                .stack
+                .get(upvalue.location())
+                .cloned()
+                .unwrap_or_else(Value::nil);
+            upvalue.close(val);
         }
-        Rc::new(UpValue::new(location))
+        self.upvalus = open;
     }
 
-    fn reset_stack(&mut self) {
+    /// Drop everything left over from the last run, globals are kept
+    pub fn reset_stack(&mut self) {
         self.stack.clear();
         self.frames.clear();
+        self.upvalus.clear();
+        self.cur_frame = CallFrame::default();
     }
 
     pub fn init(&mut self) {
@@ -405,51 +472,48 @@ impl Vm {
 
     fn call(&mut self, arg_count: usize) -> Result<(), InterpretResult> {
         if let Some(val) = self.peak(arg_count) {
-            match val {
-                Value::Obj(Object::Closure(val)) => {
+            match val.as_obj().as_deref() {
+                Some(Object::Closure(val)) => {
                     let mut frame = self.call_fun(val.clone(), arg_count)?;
                     std::mem::swap(&mut self.cur_frame, &mut frame);
                     self.frames.push_back(frame);
                 }
-                Value::Obj(Object::NativeFn(val)) => {
+                Some(Object::NativeFn(val)) => {
+                    let function = val.function;
                     let arg_idx = self.stack.len() - arg_count;
-                    let args = &self.stack.as_slices().0[arg_idx..];
-                    let result = match (val.function)(args) {
+                    let args = &self.stack.make_contiguous()[arg_idx..];
+                    let result = match function(args) {
                         Ok(val) => val,
-                        Err(err) => match err {
-                            InterpretResult::NativeFunctionError(str) => {
-                                self.runtime_error(&str);
-                                Value::Nil
-                            }
-                            InterpretResult::RuntimeError => {
-                                self.runtime_error("Invoke Native fn, unknow error");
-                                Value::Nil
-                            }
-                            _ => Value::Nil,
-                        },
+                        Err(InterpretResult::NativeFunctionError(str)) => {
+                            return Err(self.runtime_error(&str));
+                        }
+                        Err(_) => {
+                            return Err(self.runtime_error("Invoke Native fn, unknow error"));
+                        }
                     };
                     self.stack.drain(arg_idx..);
                     self.push(result);
                 }
                 _ => {
-                    self.runtime_error("Can only call functions and classes.");
-                    return Err(InterpretResult::RuntimeError);
+                    return Err(self.runtime_error("Can only call functions and classes."));
                 }
             }
         } else {
-            self.runtime_error("call no operand");
-            return Err(InterpretResult::RuntimeError);
+            return Err(self.runtime_error("call no operand"));
         }
         Ok(())
     }
 
-    fn call_fun(&mut self, clo: Closure, arg_count: usize) -> Result<CallFrame, InterpretResult> {
+    fn call_fun(
+        &mut self,
+        clo: Rc<Closure>,
+        arg_count: usize,
+    ) -> Result<CallFrame, InterpretResult> {
         if clo.function.arity != arg_count {
-            self.runtime_error(&format!(
+            return Err(self.runtime_error(&format!(
                 "Expected {} arguments but got {}.",
                 clo.function.arity, arg_count
-            ));
-            return Err(InterpretResult::RuntimeError);
+            )));
         }
         let mut frame = CallFrame::new(clo);
         frame.slot_idx = self.stack.len() - arg_count - 1;
@@ -460,15 +524,17 @@ impl Vm {
     where
         R: Into<Value>,
     {
-        if let (Some(Value::Number(b)), Some(Value::Number(a))) = (self.peak(0), self.peak(1)) {
-            let res = op(*a, *b);
+        if let (Some(b), Some(a)) = (
+            self.peak(0).and_then(|v| v.as_number()),
+            self.peak(1).and_then(|v| v.as_number()),
+        ) {
+            let res = op(a, b);
             self.pop();
             self.pop();
             self.push(res);
             InterpretResult::Ok
         } else {
-            self.runtime_error("Operand must be numbers.");
-            InterpretResult::RuntimeError
+            self.runtime_error("Operand must be numbers.")
         }
     }
 
@@ -484,13 +550,20 @@ impl Vm {
         self.cur_frame.read_consnt()
     }
 
-    fn runtime_error(&self, messgae: &str) {
-        eprintln!("{}", messgae);
+    /// Collect the stack trace and reset the vm, so it can run the next script
+    fn runtime_error(&mut self, messgae: &str) -> InterpretResult {
+        let frames = std::iter::once(&self.cur_frame)
+            .chain(self.frames.iter().rev())
+            .filter_map(frame_info)
+            .collect::<Vec<_>>();
+        let error = RuntimeError {
+            message: messgae.to_string(),
+            line: frames.first().map_or(0, |f| f.line),
+            frames,
+        };
 
-        frame_error_location(&self.cur_frame);
-        for frame in self.frames.iter().rev() {
-            frame_error_location(frame);
-        }
+        self.reset_stack();
+        InterpretResult::RuntimeError(error)
     }
 }
 
@@ -504,21 +577,16 @@ fn clock_native(_: &[Value]) -> Result<Value, InterpretResult> {
     }
 }
 
-fn frame_error_location(frame: &CallFrame) {
+fn frame_info(frame: &CallFrame) -> Option<FrameInfo> {
     let clo = &frame.closure;
     if clo.function.chunk.code().is_empty() {
-        return;
-    }
-    let offset = frame.ip - 1;
-    eprint!(
-        "[line {}] in ",
-        clo.function.chunk.line(offset).unwrap_or(0)
-    );
-    if clo.function.name.as_ref() == "" {
-        eprintln!("script");
-    } else {
-        eprintln!("{}()", clo.function.name);
+        return None;
     }
+    let offset = frame.ip.saturating_sub(1);
+    Some(FrameInfo {
+        function: clo.function.name.to_string(),
+        line: clo.function.chunk.line(offset).unwrap_or(0),
+    })
 }
 
 pub fn interpret(source: &str, vm: &mut Vm) -> InterpretResult {
@@ -532,19 +600,9 @@ pub fn interpret(source: &str, vm: &mut Vm) -> InterpretResult {
 }
 
 fn is_falsely(value: Option<&Value>) -> bool {
-    match value {
-        None => true,
-        Some(Value::Nil) => true,
-        Some(Value::Bool(bool)) => !bool,
-        Some(_) => false,
-    }
+    value.is_none_or(|v| v.is_falsey())
 }
 
 fn is_truely(value: Option<&Value>) -> bool {
-    match value {
-        Some(Value::Nil) => false,
-        Some(Value::Bool(bool)) => *bool,
-        Some(_) => true,
-        None => false,
-    }
+    value.is_some_and(|v| !v.is_falsey())
 }
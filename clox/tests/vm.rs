@@ -0,0 +1,109 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use clox::{interpret, FrameInfo, InterpretResult, Vm};
+
+/// A vm printing into a buffer
+fn vm() -> (Vm, Rc<RefCell<Vec<u8>>>) {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    vm.set_output(output.clone());
+    (vm, output)
+}
+
+fn take(output: &RefCell<Vec<u8>>) -> String {
+    String::from_utf8(output.take()).unwrap()
+}
+
+#[test]
+fn print_goes_to_the_output() {
+    let (mut vm, output) = vm();
+    assert_eq!(
+        interpret("print 1 + 2;\nprint \"a\";", &mut vm),
+        InterpretResult::Ok
+    );
+    assert_eq!(take(&output), "3\na\n");
+}
+
+#[test]
+fn runtime_error_has_the_call_stack() {
+    let (mut vm, _) = vm();
+    let source = "fn inner() {
+  return -\"x\";
+}
+fn outer() {
+  inner();
+}
+outer();";
+    let err = match interpret(source, &mut vm) {
+        InterpretResult::RuntimeError(err) => err,
+        result => panic!("unexpected {:?}", result),
+    };
+    assert_eq!(err.message, "Operand must be a number");
+    assert_eq!(err.line, 2);
+    let frame = |function: &str, line| FrameInfo {
+        function: function.to_string(),
+        line,
+    };
+    assert_eq!(
+        err.frames,
+        vec![frame("inner", 2), frame("outer", 5), frame("", 7)]
+    );
+    assert_eq!(
+        err.to_string(),
+        "Operand must be a number\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script\n"
+    );
+}
+
+#[test]
+fn vm_runs_again_after_an_error() {
+    let (mut vm, output) = vm();
+    assert_eq!(interpret("var g = 1;", &mut vm), InterpretResult::Ok);
+    let source = "fn fail() { return -\"x\"; }\nfail();";
+    assert!(matches!(
+        interpret(source, &mut vm),
+        InterpretResult::RuntimeError(_)
+    ));
+
+    // globals are kept, the stack of the failed run is gone
+    let source = "fn add(a, b) { return a + b; }\nprint add(g, 2);";
+    assert_eq!(interpret(source, &mut vm), InterpretResult::Ok);
+    assert_eq!(take(&output), "3\n");
+}
+
+#[test]
+fn closure_outlives_the_block_it_captured() {
+    let (mut vm, output) = vm();
+    let source = "var f;
+{
+  var a = \"block\";
+  fn g() {
+    print a;
+  }
+  f = g;
+}
+var b = \"other\";
+f();";
+    assert_eq!(interpret(source, &mut vm), InterpretResult::Ok);
+    assert_eq!(take(&output), "block\n");
+}
+
+#[test]
+fn closures_share_a_captured_variable() {
+    let (mut vm, output) = vm();
+    let source = "fn counter() {
+  var n = 0;
+  fn inc() {
+    n = n + 1;
+  }
+  fn get() {
+    return n;
+  }
+  inc();
+  return get;
+}
+var get = counter();
+print get();";
+    assert_eq!(interpret(source, &mut vm), InterpretResult::Ok);
+    assert_eq!(take(&output), "1\n");
+}
@@ -0,0 +1,167 @@
+//! Time from a visitor connecting to its first echoed byte, with a connection per
+//! visitor, with a pool of idle ones and with multiplexing, over a link with an
+//! artificial round trip time
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rathole::{run, Cli};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+use tokio::time;
+
+// Each direction of the link between the client and the server
+const ONE_WAY_DELAY: Duration = Duration::from_millis(10);
+
+async fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port())
+}
+
+async fn run_echo_server(listener: TcpListener) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let (mut rd, mut wr) = conn.split();
+            let _ = io::copy(&mut rd, &mut wr).await;
+        });
+    }
+}
+
+/// Copy `rd` to `wr`, delivering every chunk `ONE_WAY_DELAY` after it arrived
+async fn delayed_copy(mut rd: io::ReadHalf<TcpStream>, mut wr: io::WriteHalf<TcpStream>) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(n) = rd.read(&mut buf).await {
+            if n == 0 || tx.send((time::Instant::now(), buf[..n].to_vec())).is_err() {
+                break;
+            }
+        }
+    });
+    while let Some((at, chunk)) = rx.recv().await {
+        time::sleep_until(at + ONE_WAY_DELAY).await;
+        if wr.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Forward connections from `listener` to `to` as if they crossed a slow link
+async fn run_delay_proxy(listener: TcpListener, to: String) {
+    while let Ok((conn, _)) = listener.accept().await {
+        let to = to.clone();
+        tokio::spawn(async move {
+            let Ok(upstream) = TcpStream::connect(&to).await else {
+                return;
+            };
+            let _ = conn.set_nodelay(true);
+            let _ = upstream.set_nodelay(true);
+            let (conn_rd, conn_wr) = io::split(conn);
+            let (up_rd, up_wr) = io::split(upstream);
+            tokio::join!(delayed_copy(conn_rd, up_wr), delayed_copy(up_rd, conn_wr));
+        });
+    }
+}
+
+async fn echo_once(addr: &str) -> Result<()> {
+    let mut conn = TcpStream::connect(addr).await?;
+    conn.write_all(b"x").await?;
+    let mut buf = [0u8; 1];
+    conn.read_exact(&mut buf).await?;
+    Ok(())
+}
+
+/// Start a server, a delaying proxy and a client with `mode` appended to `[client]`
+/// and `service` to its service, returns the visitor address
+async fn start_tunnel(
+    dir: &Path,
+    mode: &str,
+    service: &str,
+) -> Result<(String, broadcast::Sender<bool>)> {
+    let server_port = free_port().await?;
+    let proxy_port = free_port().await?;
+    let visitor_port = free_port().await?;
+    let local_port = free_port().await?;
+
+    let echo = TcpListener::bind(("127.0.0.1", local_port)).await?;
+    tokio::spawn(run_echo_server(echo));
+    let proxy = TcpListener::bind(("127.0.0.1", proxy_port)).await?;
+    tokio::spawn(run_delay_proxy(proxy, format!("127.0.0.1:{}", server_port)));
+
+    let server_config = dir.join("server.toml");
+    fs::write(
+        &server_config,
+        format!(
+            "[server]\nbind_addr = \"127.0.0.1:{}\"\ndefault_token = \"bench\"\n\
+            [server.services.echo]\nbind_addrs = [\"127.0.0.1:{}\"]\n",
+            server_port, visitor_port
+        ),
+    )?;
+    let client_config = dir.join("client.toml");
+    fs::write(
+        &client_config,
+        format!(
+            "[client]\nremote_addr = \"127.0.0.1:{}\"\ndefault_token = \"bench\"\n{}\n\
+            [client.services.echo]\nlocal_addr = \"127.0.0.1:{}\"\n{}\n",
+            proxy_port, mode, local_port, service
+        ),
+    )?;
+
+    let (shutdown_tx, _) = broadcast::channel(1);
+    for (path, server) in [(server_config, true), (client_config, false)] {
+        let cli = Cli {
+            config_path: Some(path),
+            server,
+            client: !server,
+            ..Default::default()
+        };
+        tokio::spawn(run(cli, shutdown_tx.clone()));
+    }
+
+    let visitor_addr = format!("127.0.0.1:{}", visitor_port);
+    let deadline = time::Instant::now() + Duration::from_secs(10);
+    while time::timeout(Duration::from_secs(1), echo_once(&visitor_addr))
+        .await
+        .map_or(true, |res| res.is_err())
+    {
+        if time::Instant::now() > deadline {
+            anyhow::bail!("The tunnel didn't come up");
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    // the mux channels and the pool come up right after the control channel
+    time::sleep(Duration::from_millis(200)).await;
+    Ok((visitor_addr, shutdown_tx))
+}
+
+fn connection_setup(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("connection_setup");
+    group.sample_size(20);
+
+    let modes = [
+        ("direct", "", ""),
+        ("pool", "", "pool_size = 4"),
+        ("multiplex", "[client.multiplex]", ""),
+    ];
+    for (name, mode, service) in modes {
+        let dir = tempfile::tempdir().unwrap();
+        let (visitor_addr, shutdown_tx) = rt
+            .block_on(start_tunnel(dir.path(), mode, service))
+            .unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.to_async(&rt)
+                .iter(|| async { echo_once(&visitor_addr).await.unwrap() })
+        });
+        let _ = shutdown_tx.send(true);
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, connection_setup);
+criterion_main!(benches);
@@ -0,0 +1,216 @@
+use std::fmt::Write;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, instrument, Instrument, Span};
+
+// Requests are a single line and some headers, anything bigger is not for us
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub control_channel: bool,
+    /// Seconds since the control channel was established
+    pub uptime: Option<u64>,
+    pub active_data_channels: usize,
+    /// visitors -> service
+    pub bytes_in: u64,
+    /// service -> visitors
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    services: Vec<ServiceStatus>,
+}
+
+/// Where the admin endpoint gets its numbers from, implemented by the server and the client
+#[async_trait]
+pub trait StatusSource: Send + Sync + 'static {
+    async fn status(&self) -> Vec<ServiceStatus>;
+}
+
+/// Serve `GET /status` as JSON and `GET /metrics` in the Prometheus text format
+#[instrument(skip_all, fields(bind_addr = %bind_addr))]
+pub async fn run_admin<S: StatusSource>(bind_addr: String, source: Arc<S>) -> Result<()> {
+    let listener = TcpListener::bind(&bind_addr)
+        .await
+        .with_context(|| format!("Failed to listen at the admin address {}", bind_addr))?;
+    info!("Admin endpoint listening");
+
+    loop {
+        let (conn, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+
+        let source = source.clone();
+        tokio::spawn(
+            async move {
+                if let Err(e) = handle_request(conn, source).await {
+                    debug!("Admin request from {} failed: {:?}", addr, e);
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+}
+
+async fn handle_request<S: StatusSource>(mut conn: TcpStream, source: Arc<S>) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/status") => {
+            let status = Status {
+                services: sorted(source.status().await),
+            };
+            (
+                "200 OK",
+                "application/json",
+                serde_json::to_string(&status)?,
+            )
+        }
+        ("GET", "/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render_metrics(&sorted(source.status().await)),
+        ),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not Found\n".into()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".into(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    conn.write_all(response.as_bytes()).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+fn sorted(mut services: Vec<ServiceStatus>) -> Vec<ServiceStatus> {
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    services
+}
+
+fn render_metrics(services: &[ServiceStatus]) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&ServiceStatus) -> u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for s in services {
+            let service = escape_label(&s.name);
+            let _ = writeln!(out, "{}{{service=\"{}\"}} {}", name, service, value(s));
+        }
+    };
+
+    metric(
+        "rathole_control_channel_up",
+        "gauge",
+        "Whether the service has a live control channel",
+        &|s| s.control_channel as u64,
+    );
+    metric(
+        "rathole_control_channel_uptime_seconds",
+        "gauge",
+        "Seconds since the control channel was established",
+        &|s| s.uptime.unwrap_or(0),
+    );
+    metric(
+        "rathole_active_data_channels",
+        "gauge",
+        "Visitors being forwarded right now",
+        &|s| s.active_data_channels as u64,
+    );
+    metric(
+        "rathole_received_bytes_total",
+        "counter",
+        "Bytes forwarded from visitors to the service",
+        &|s| s.bytes_in,
+    );
+    metric(
+        "rathole_sent_bytes_total",
+        "counter",
+        "Bytes forwarded from the service to visitors",
+        &|s| s.bytes_out,
+    );
+
+    out
+}
+
+/// Escape a label value the way the exposition format wants it
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let services = vec![ServiceStatus {
+            name: "ssh".into(),
+            control_channel: true,
+            uptime: Some(42),
+            active_data_channels: 2,
+            bytes_in: 100,
+            bytes_out: 2000,
+        }];
+        let metrics = render_metrics(&services);
+
+        assert!(metrics.contains("# TYPE rathole_control_channel_up gauge\n"));
+        assert!(metrics.contains("rathole_control_channel_up{service=\"ssh\"} 1\n"));
+        assert!(metrics.contains("rathole_control_channel_uptime_seconds{service=\"ssh\"} 42\n"));
+        assert!(metrics.contains("rathole_active_data_channels{service=\"ssh\"} 2\n"));
+        assert!(metrics.contains("rathole_received_bytes_total{service=\"ssh\"} 100\n"));
+        assert!(metrics.contains("rathole_sent_bytes_total{service=\"ssh\"} 2000\n"));
+    }
+
+    #[test]
+    fn test_label_values_escaped() {
+        let services = vec![ServiceStatus {
+            name: "a\"b\\c".into(),
+            control_channel: false,
+            uptime: None,
+            active_data_channels: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+        }];
+        let metrics = render_metrics(&services);
+
+        assert!(metrics.contains("rathole_control_channel_up{service=\"a\\\"b\\\\c\"} 0\n"));
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+    }
+}
@@ -57,4 +57,8 @@ pub struct Cli {
     /// Run as a client
     #[clap(long, short, group = "mode")]
     pub client: bool,
+
+    /// Validate the configuration and exit
+    #[clap(long)]
+    pub check: bool,
 }
@@ -1,26 +1,51 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
-use tokio::io::{copy_bidirectional, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{broadcast, oneshot};
-use tokio::time;
-use tracing::{debug, error, info, instrument, Instrument, Span};
-
-use crate::config::{ClientConfig, ClientServiceConfig, TransportType};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{debug, error, info, instrument, warn, Instrument, Span};
+
+use crate::admin::{self, ServiceStatus, StatusSource};
+use crate::config::{
+    ClientConfig, ClientServiceConfig, MultiplexConfig, ProxyProtocol, TransportType,
+};
+use crate::config_watcher::{ClientServiceChange, ConfigChange};
+use crate::mux::{self, DataChannel, Side};
 use crate::protocol::{
-    read_ack, read_control_cmd, read_data_cmd, Ack, Auth, ControlChannelCmd, DataChannelCmd, Hello,
-    HASH_WIDTH_IN_BYTES,
+    read_ack, read_data_cmd, read_frame, write_message, Ack, Auth, Capabilities, ControlChannelCmd,
+    DataChannelCmd, Handshake, Hello, UdpTraffic, UDP_BUFFER_SIZE,
 };
+use crate::traffic::{self, ServiceTraffic};
+#[cfg(feature = "tls")]
+use crate::transport::TlsTransport;
+#[cfg(feature = "websocket")]
+use crate::transport::WebsocketTransport;
 use crate::transport::{TcpTransport, Transport};
-use crate::{protocol, Config};
+use crate::{protocol, proxy_protocol, Config};
 
 type ServiceDigest = protocol::Digest;
 
-pub async fn run_client(config: &Config, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+const CHAN_SIZE: usize = 2048; // The capacity of various chans
+
+/// A visitor's udp session is dropped after this long without traffic in either direction
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub async fn run_client(
+    config: &Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    update_rx: mpsc::Receiver<ConfigChange>,
+) -> Result<()> {
     let config = match &config.client {
         Some(v) => v,
         None => {
@@ -28,17 +53,37 @@ pub async fn run_client(config: &Config, shutdown_rx: broadcast::Receiver<bool>)
         }
     };
 
-    match config.transport {
+    match config.transport.transport_type {
         TransportType::Tcp => {
             let mut client = Client::<TcpTransport>::from(config).await?;
-            client.run(shutdown_rx).await
+            client.run(shutdown_rx, update_rx).await
+        }
+        TransportType::Tls => {
+            #[cfg(not(feature = "tls"))]
+            crate::helper::feature_not_compile("tls");
+
+            #[cfg(feature = "tls")]
+            {
+                let mut client = Client::<TlsTransport>::from(config).await?;
+                client.run(shutdown_rx, update_rx).await
+            }
+        }
+        TransportType::Websocket => {
+            #[cfg(not(feature = "websocket"))]
+            crate::helper::feature_not_compile("websocket");
+
+            #[cfg(feature = "websocket")]
+            {
+                let mut client = Client::<WebsocketTransport>::from(config).await?;
+                client.run(shutdown_rx, update_rx).await
+            }
         }
     }
 }
 
 struct Client<'a, T: Transport> {
     config: &'a ClientConfig,
-    service_handles: HashMap<String, ControlChannelHandle>,
+    service_handles: Arc<RwLock<HashMap<String, ControlChannelHandle>>>,
     transport: Arc<T>,
 }
 
@@ -46,7 +91,7 @@ impl<'a, T: 'static + Transport> Client<'a, T> {
     async fn from(config: &'a ClientConfig) -> Result<Client<'a, T>> {
         Ok(Client {
             config,
-            service_handles: HashMap::new(),
+            service_handles: Arc::new(RwLock::new(HashMap::new())),
             transport: Arc::new(
                 T::new(&config.transport)
                     .await
@@ -55,42 +100,115 @@ impl<'a, T: 'static + Transport> Client<'a, T> {
         })
     }
 
-    async fn run(&mut self, mut shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
-        for (name, config) in &self.config.services {
-            let handle = ControlChannelHandle::run(
-                (*config).clone(),
-                self.config.remote_addr.clone(),
-                self.transport.clone(),
-            );
-            self.service_handles.insert(name.clone(), handle);
+    async fn run(
+        &mut self,
+        mut shutdown_rx: broadcast::Receiver<bool>,
+        mut update_rx: mpsc::Receiver<ConfigChange>,
+    ) -> Result<()> {
+        for config in self.config.services.values() {
+            self.start_service(config.clone()).await;
         }
 
+        let admin = self.config.admin.as_ref().map(|config| {
+            let status = Arc::new(ClientStatus {
+                service_handles: self.service_handles.clone(),
+            });
+            tokio::spawn(admin::run_admin(config.bind_addr.clone(), status))
+        });
+
         loop {
             tokio::select! {
                 val = shutdown_rx.recv() => {
-                    match val {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("Unable to listen for shutdown signal: {}", err);
-                        }
+                    if let Err(err) = val {
+                        error!("Unable to listen for shutdown signal: {}", err);
                     }
                     break;
                 },
+                Some(event) = update_rx.recv() => {
+                    if let ConfigChange::ClientChange(change) = event {
+                        self.handle_hot_reload(change).await;
+                    }
+                }
             }
         }
 
-        for (_, handle) in self.service_handles.drain() {
+        if let Some(admin) = admin {
+            admin.abort();
+        }
+        for (_, handle) in self.service_handles.write().await.drain() {
             handle.shutdown();
         }
 
         Ok(())
     }
+
+    async fn start_service(&mut self, config: ClientServiceConfig) {
+        let name = config.name.clone();
+        let handle = ControlChannelHandle::run(
+            config,
+            self.config.remote_addr.clone(),
+            self.transport.clone(),
+            self.config.heartbeat_timeout,
+            self.config.multiplex.clone(),
+        );
+        self.service_handles.write().await.insert(name, handle);
+    }
+
+    async fn handle_hot_reload(&mut self, change: ClientServiceChange) {
+        match change {
+            ClientServiceChange::Add(config) => {
+                info!("Adding service {}", config.name);
+                self.start_service(config).await;
+            }
+            ClientServiceChange::Delete(name) => {
+                info!("Deleting service {}", name);
+                if let Some(handle) = self.service_handles.write().await.remove(&name) {
+                    handle.shutdown();
+                }
+            }
+        }
+    }
+}
+
+struct ClientStatus {
+    service_handles: Arc<RwLock<HashMap<String, ControlChannelHandle>>>,
+}
+
+#[async_trait]
+impl StatusSource for ClientStatus {
+    async fn status(&self) -> Vec<ServiceStatus> {
+        let handles = self.service_handles.read().await;
+        handles
+            .iter()
+            .map(|(name, handle)| {
+                let established = *handle.status.established.lock().unwrap();
+                let (bytes_in, bytes_out) = handle.status.traffic.snapshot();
+                ServiceStatus {
+                    name: name.clone(),
+                    control_channel: established.is_some(),
+                    uptime: established.map(|t| t.elapsed().as_secs()),
+                    active_data_channels: handle.status.traffic.active.load(Ordering::Relaxed),
+                    bytes_in,
+                    bytes_out,
+                }
+            })
+            .collect()
+    }
+}
+
+/// What a control channel and its data channels are up to, for the admin endpoint
+#[derive(Default)]
+struct ChannelStatus {
+    // when the current control channel was established, none while reconnecting
+    established: Mutex<Option<Instant>>,
+    traffic: ServiceTraffic,
 }
 
 // Handle of a control channel
 // Dropping it will also drop the actual control channel
 struct ControlChannelHandle {
     shutdown_tx: oneshot::Sender<u8>,
+    status: Arc<ChannelStatus>,
 }
 
 impl ControlChannelHandle {
@@ -99,38 +217,75 @@ impl ControlChannelHandle {
         service: ClientServiceConfig,
         remote_addr: String,
         transport: Arc<T>,
+        heartbeat_timeout: u64,
+        multiplex: Option<MultiplexConfig>,
     ) -> Self {
         let digest = protocol::digest(service.name.as_bytes());
 
         info!("Starting {}", hex::encode(digest));
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let status = Arc::new(ChannelStatus::default());
         let mut control_channel = ControlChannel {
             digest,
             service,
             shutdown_rx,
             remote_addr,
             transport,
+            heartbeat_timeout: Duration::from_secs(heartbeat_timeout),
+            multiplex,
+            status: status.clone(),
         };
 
-        tokio::spawn(async move {
-            while let Err(err) = control_channel
-                .run()
-                .await
-                .with_context(|| "Failed to run the control channel")
-            {
-                if control_channel.shutdown_rx.try_recv()
-                    != Err(oneshot::error::TryRecvError::Empty)
-                {
-                    break;
-                }
+        tokio::spawn(
+            async move {
+                // Keep reconnecting until shutdown, backing off while the server is unreachable
+                let mut backoff = ExponentialBackoff {
+                    max_interval: Duration::from_secs(60),
+                    max_elapsed_time: None,
+                    ..Default::default()
+                };
+
+                loop {
+                    let err = match control_channel.handshake().await {
+                        Ok((conn, nonce, negotiated)) => {
+                            backoff.reset();
+                            *control_channel.status.established.lock().unwrap() =
+                                Some(Instant::now());
+                            let res = control_channel.serve(conn, nonce, negotiated).await;
+                            *control_channel.status.established.lock().unwrap() = None;
+                            match res {
+                                Ok(()) => break,
+                                Err(err) => err,
+                            }
+                        }
+                        Err(err) => err,
+                    };
 
-                let duration = Duration::from_secs(3);
-                error!("{:?}\n\nRetry in {:?}...", err, duration);
-                time::sleep(duration).await;
+                    if control_channel.shutdown_rx.try_recv()
+                        != Err(oneshot::error::TryRecvError::Empty)
+                    {
+                        break;
+                    }
+
+                    let duration = backoff.next_backoff().unwrap_or(backoff.max_interval);
+                    error!(
+                        "{:?}\n\nRetry in {:?}...",
+                        err.context("Failed to run the control channel"),
+                        duration
+                    );
+                    tokio::select! {
+                        _ = time::sleep(duration) => {},
+                        _ = &mut control_channel.shutdown_rx => break,
+                    }
+                }
             }
-        });
+            .instrument(Span::current()),
+        );
 
-        Self { shutdown_tx }
+        Self {
+            shutdown_tx,
+            status,
+        }
     }
 
     fn shutdown(self) {
@@ -144,11 +299,16 @@ struct ControlChannel<T: Transport> {
     shutdown_rx: oneshot::Receiver<u8>,
     remote_addr: String,
     transport: Arc<T>,
+    // zero disables the check
+    heartbeat_timeout: Duration,
+    multiplex: Option<MultiplexConfig>,
+    status: Arc<ChannelStatus>,
 }
 
 impl<T: 'static + Transport> ControlChannel<T> {
-    #[instrument(skip(self), fields(service = %self.service.name))]
-    async fn run(&mut self) -> Result<()> {
+    /// Connect to the server and authenticate, returns the connection, the nonce and
+    /// what the protocol versions of both sides agreed on
+    async fn handshake(&mut self) -> Result<(T::Stream, protocol::Digest, Handshake)> {
         let mut conn_control = self
             .transport
             .connect(&self.remote_addr)
@@ -157,22 +317,21 @@ impl<T: 'static + Transport> ControlChannel<T> {
 
         //send hello
         debug!("Sending hello");
-        let hello_send = Hello::ControlChannel(self.digest[..].try_into().unwrap());
-        conn_control
-            .write_all(&bincode::serialize(&hello_send).unwrap())
-            .await?;
+        let hello_send = Hello::ControlChannel(Handshake::local(), self.digest);
+        write_message(&mut conn_control, &hello_send).await?;
 
         //reading hello
         debug!("Reading hello");
-        let nonce = match protocol::read_hello(&mut conn_control)
+        let (negotiated, nonce) = match protocol::read_hello(&mut conn_control)
             .await
             .with_context(|| "Failed to read hello from the sever")?
         {
-            Hello::ControlChannel(d) => d,
+            Hello::ControlChannel(server, d) => (Handshake::local().negotiate(&server)?, d),
             _ => {
                 bail!("Unexpected type of hello");
             }
         };
+        debug!("Speaking protocol version {}", negotiated.version);
 
         // Read Ack
         debug!("Sending auth");
@@ -180,10 +339,7 @@ impl<T: 'static + Transport> ControlChannel<T> {
         concat.extend_from_slice(&nonce);
 
         let session_key = protocol::digest(&concat);
-        let auth = Auth(session_key);
-        conn_control
-            .write_all(&bincode::serialize(&auth).unwrap())
-            .await?;
+        write_message(&mut conn_control, &Auth(session_key)).await?;
 
         //Read ack
         debug!("Reading ack");
@@ -198,35 +354,89 @@ impl<T: 'static + Transport> ControlChannel<T> {
         }
 
         info!("Control channel established");
+        Ok((conn_control, nonce, negotiated))
+    }
 
+    /// Serve the commands of an established control channel until shutdown
+    async fn serve(
+        &mut self,
+        mut conn_control: T::Stream,
+        nonce: protocol::Digest,
+        negotiated: Handshake,
+    ) -> Result<()> {
         let remote_addr = self.remote_addr.clone();
         let local_addr = self.service.local_addr.clone();
-        let session_key = self.digest;
+        let session_key = protocol::data_channel_key(self.service.token.as_ref().unwrap(), &nonce);
+        let muxing =
+            self.multiplex.is_some() && negotiated.capabilities.contains(Capabilities::MULTIPLEX);
         let data_ch_args = Arc::new(RunDataChannelArgs {
             session_key,
             remote_addr,
             local_addr,
+            proxy_protocol: self.service.proxy_protocol,
             connector: self.transport.clone(),
+            status: self.status.clone(),
         });
 
+        let mux_channels = match &self.multiplex {
+            Some(m) if muxing => (0..m.connections)
+                .map(|_| {
+                    tokio::spawn(run_mux_channel(data_ch_args.clone()).instrument(Span::current()))
+                })
+                .collect(),
+            Some(_) => {
+                warn!("The server doesn't support multiplexing, using a connection per visitor");
+                vec![]
+            }
+            None => vec![],
+        };
+        let _mux_channels = AbortOnDrop(mux_channels);
+
+        // a pool of idle data channels. The server replaces each one it uses or finds
+        // closed with a `CreateDataChannel`, the client never reopens them itself
+        if !muxing {
+            for _ in 0..self.service.pool_size {
+                spawn_data_channel(data_ch_args.clone());
+            }
+        }
+
+        // a server without heartbeats stays silent while idle
+        let heartbeat_timeout = if negotiated.capabilities.contains(Capabilities::HEARTBEAT) {
+            self.heartbeat_timeout
+        } else {
+            Duration::ZERO
+        };
+        let deadline = time::sleep(heartbeat_timeout);
+        tokio::pin!(deadline);
+
         loop {
             tokio::select! {
-                val = read_control_cmd(&mut conn_control) => {
-                    let val = val?;
-                    match val {
+                frame = read_frame(&mut conn_control) => {
+                    let frame = frame.with_context(|| "Failed to read control cmd")?;
+                    deadline.as_mut().reset(time::Instant::now() + heartbeat_timeout);
+                    let cmd = match protocol::decode(&frame) {
+                        Ok(cmd) => cmd,
+                        Err(e) => {
+                            // something of a newer server that doesn't need an answer
+                            warn!("Ignoring an unknown control command: {:#}", e);
+                            continue;
+                        }
+                    };
+                    match cmd {
                         ControlChannelCmd::CreateDataChannel => {
-                            let args = data_ch_args.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = run_data_channel(args).await.with_context(|| "Failed to run the data channel") {
-                                    error!("{:?}", e);
-                                }
-                            }.instrument(Span::current()));
+                            spawn_data_channel(data_ch_args.clone());
+                        }
+                        ControlChannelCmd::Heartbeat => {
+                            debug!("Heartbeat");
+                            write_message(&mut conn_control, &Hello::Heartbeat).await?;
                         }
                     }
                 },
+                _ = &mut deadline, if !heartbeat_timeout.is_zero() => {
+                    bail!("No heartbeat from the server for {:?}", heartbeat_timeout);
+                },
                 _ = &mut self.shutdown_rx => {
-                    let close_send = Hello::ControlChannelClose;
-                    conn_control.write_all(&bincode::serialize(&close_send).unwrap()).await?;
+                    write_message(&mut conn_control, &Hello::ControlChannelClose).await?;
                     info!("Control channel shutting down..");
                     break;
                 }
@@ -241,26 +451,113 @@ struct RunDataChannelArgs<T: Transport> {
     session_key: ServiceDigest,
     remote_addr: String,
     local_addr: String,
+    proxy_protocol: Option<ProxyProtocol>,
     connector: Arc<T>,
+    status: Arc<ChannelStatus>,
 }
 
-async fn run_data_channel<T: Transport>(args: Arc<RunDataChannelArgs<T>>) -> Result<()> {
-    let mut conn = do_data_channel_handshake(args.clone()).await?;
+/// Aborts the tasks when dropped, however the owner returns
+struct AbortOnDrop(Vec<JoinHandle<()>>);
 
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+fn spawn_data_channel<T: 'static + Transport>(args: Arc<RunDataChannelArgs<T>>) {
+    tokio::spawn(
+        async move {
+            if let Err(e) = run_data_channel(args)
+                .await
+                .with_context(|| "Failed to run the data channel")
+            {
+                error!("{:?}", e);
+            }
+        }
+        .instrument(Span::current()),
+    );
+}
+
+async fn run_data_channel<T: 'static + Transport>(args: Arc<RunDataChannelArgs<T>>) -> Result<()> {
+    let mut conn = do_data_channel_handshake(&args, Hello::DataChannel(args.session_key)).await?;
     info!("New data channel created waiting");
-    match read_data_cmd(&mut conn).await? {
-        DataChannelCmd::StartForwardTcp => {
-            run_data_channel_for_tcp::<T>(conn, &args.local_addr).await?;
+    let cmd = read_data_cmd(&mut conn).await?;
+    forward_data_channel(DataChannel::Direct(conn), cmd, &args).await
+}
+
+/// Keep a mux channel to the server up and serve the streams the server opens on it,
+/// until aborted along with the control channel
+async fn run_mux_channel<T: 'static + Transport>(args: Arc<RunDataChannelArgs<T>>) {
+    let mut backoff = ExponentialBackoff {
+        max_interval: Duration::from_secs(10),
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+
+    loop {
+        match do_data_channel_handshake(&args, Hello::MuxChannel(args.session_key)).await {
+            Ok(conn) => {
+                backoff.reset();
+                info!("Mux channel established");
+                let (_session, mut incoming, driver) = mux::session(conn, Side::Client);
+                tokio::pin!(driver);
+                let res = loop {
+                    tokio::select! {
+                        res = &mut driver => break res,
+                        Some(stream) = incoming.recv() => {
+                            let args = args.clone();
+                            tokio::spawn(async move {
+                                let mut stream = DataChannel::Muxed(stream);
+                                let res = match read_data_cmd(&mut stream).await {
+                                    Ok(cmd) => forward_data_channel(stream, cmd, &args).await,
+                                    Err(e) => Err(e),
+                                };
+                                if let Err(e) = res.with_context(|| "Failed to run the data channel") {
+                                    error!("{:?}", e);
+                                }
+                            }.instrument(Span::current()));
+                        }
+                    }
+                };
+                match res {
+                    Ok(()) => info!("Mux channel closed by the server"),
+                    Err(e) => error!("{:?}", e.context("Mux channel failed")),
+                }
+            }
+            Err(e) => error!("{:?}", e.context("Failed to establish a mux channel")),
+        }
+
+        time::sleep(backoff.next_backoff().unwrap_or(backoff.max_interval)).await;
+    }
+}
+
+async fn forward_data_channel<T: 'static + Transport>(
+    conn: DataChannel<T::Stream>,
+    cmd: DataChannelCmd,
+    args: &RunDataChannelArgs<T>,
+) -> Result<()> {
+    match cmd {
+        DataChannelCmd::StartForwardTcp { visitor, bind } => {
+            let proxy_header = args
+                .proxy_protocol
+                .map(|version| proxy_protocol::header(version, visitor, bind));
+            run_data_channel_for_tcp::<T>(conn, &args.local_addr, proxy_header, &args.status)
+                .await?;
         }
         DataChannelCmd::StartForwardUdp => {
-            panic!("Forward udp is not support")
+            run_data_channel_for_udp::<T>(conn, &args.local_addr, args.status.clone()).await?;
         }
     }
     Ok(())
 }
 
+/// Connect to the server and join the control channel with `hello`
 async fn do_data_channel_handshake<T: Transport>(
-    args: Arc<RunDataChannelArgs<T>>,
+    args: &RunDataChannelArgs<T>,
+    hello: Hello,
 ) -> Result<T::Stream> {
     let backoff = ExponentialBackoff {
         max_interval: Duration::from_millis(100),
@@ -277,23 +574,164 @@ async fn do_data_channel_handshake<T: Transport>(
     })
     .await?;
 
-    let v: &[u8; HASH_WIDTH_IN_BYTES] = args.session_key[..].try_into().unwrap();
-    let hello = Hello::DataChannel(v.to_owned());
-    conn.write_all(&bincode::serialize(&hello).unwrap()).await?;
+    write_message(&mut conn, &hello).await?;
 
-    Ok(conn)
+    match read_ack(&mut conn).await? {
+        Ack::Ok => Ok(conn),
+        v => Err(anyhow!("{:?}", v)).with_context(|| "Data channel authentication failed"),
+    }
 }
 
 async fn run_data_channel_for_tcp<T: Transport>(
-    mut conn: T::Stream,
+    mut conn: DataChannel<T::Stream>,
     local_addr: &str,
+    proxy_header: Option<Vec<u8>>,
+    status: &ChannelStatus,
 ) -> Result<()> {
     info!("New data channel starts forwarding to {:?}", local_addr);
     let mut local = TcpStream::connect(local_addr)
         .await
         .with_context(|| "Failed to connect to local_addr")?;
-    let _ = copy_bidirectional(&mut conn, &mut local).await;
+    if let Some(header) = proxy_header {
+        local
+            .write_all(&header)
+            .await
+            .with_context(|| "Failed to write the PROXY protocol header")?;
+    }
+    let _ = traffic::copy_bidirectional(&mut conn, &mut local, &status.traffic).await;
 
     info!("Data channel Stp forwarding to {:?}", local_addr);
     Ok(())
 }
+
+/// Datagrams of every visitor share this data channel, each visitor gets its own
+/// socket to `local_addr` so the replies can be told apart
+async fn run_data_channel_for_udp<T: 'static + Transport>(
+    conn: DataChannel<T::Stream>,
+    local_addr: &str,
+    status: Arc<ChannelStatus>,
+) -> Result<()> {
+    info!("New data channel starts forwarding udp to {:?}", local_addr);
+    let local_addr = lookup_host(local_addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("Failed to resolve local_addr {}", local_addr))?;
+
+    let (mut rd, mut wr) = io::split(conn);
+
+    // local services -> data channel
+    let (outbound_tx, mut outbound_rx) = mpsc::channel::<UdpTraffic>(CHAN_SIZE);
+    let outbound_status = status.clone();
+    let writer = tokio::spawn(async move {
+        while let Some(traffic) = outbound_rx.recv().await {
+            outbound_status.traffic.outbound(traffic.data.len()).await;
+            if let Err(e) = traffic.write(&mut wr).await {
+                error!("{:?}", e);
+                break;
+            }
+        }
+    });
+
+    // data channel -> local services
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let res = loop {
+        let UdpTraffic { from, data } = match UdpTraffic::read(&mut rd).await {
+            Ok(traffic) => traffic,
+            Err(e) => break Err(e),
+        };
+
+        let session = match sessions.get(&from) {
+            Some(tx) if !tx.is_closed() => tx.clone(),
+            _ => {
+                sessions.retain(|_, tx| !tx.is_closed());
+
+                let socket = match bind_udp_for(local_addr).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("{:?}", e);
+                        continue;
+                    }
+                };
+                let (inbound_tx, inbound_rx) = mpsc::channel(CHAN_SIZE);
+                tokio::spawn(
+                    run_udp_session(socket, from, inbound_rx, outbound_tx.clone())
+                        .instrument(Span::current()),
+                );
+                debug!("New udp session for {}", from);
+                sessions.insert(from, inbound_tx.clone());
+                inbound_tx
+            }
+        };
+
+        status.traffic.inbound(data.len()).await;
+        // a visitor whose local service can't keep up loses datagrams like on any
+        // udp path, waiting for it would stall the others on this data channel
+        if let Err(TrySendError::Full(_)) = session.try_send(data) {
+            debug!("Dropped a datagram for {}: its session is full", from);
+        }
+    };
+
+    writer.abort();
+    info!("Data channel stop forwarding udp to {:?}", local_addr);
+    res
+}
+
+async fn bind_udp_for(local_addr: SocketAddr) -> Result<UdpSocket> {
+    let bind_addr = if local_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket
+        .connect(local_addr)
+        .await
+        .with_context(|| "Failed to connect to local_addr")?;
+    Ok(socket)
+}
+
+async fn run_udp_session(
+    socket: UdpSocket,
+    from: SocketAddr,
+    mut inbound_rx: mpsc::Receiver<Vec<u8>>,
+    outbound_tx: mpsc::Sender<UdpTraffic>,
+) {
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            val = socket.recv(&mut buf) => {
+                match val {
+                    Ok(n) => {
+                        let traffic = UdpTraffic { from, data: buf[..n].to_vec() };
+                        match outbound_tx.try_send(traffic) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                debug!("Dropped a datagram to {}: the data channel is full", from);
+                            }
+                            Err(TrySendError::Closed(_)) => break,
+                        }
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        break;
+                    }
+                }
+            },
+            val = inbound_rx.recv() => {
+                match val {
+                    Some(data) => {
+                        if let Err(e) = socket.send(&data).await {
+                            error!("{}", e);
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            },
+            _ = time::sleep(UDP_TIMEOUT) => {
+                break;
+            }
+        }
+    }
+    debug!("Udp session for {} closed", from);
+}
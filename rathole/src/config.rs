@@ -1,32 +1,107 @@
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
 use std::fs;
+use std::net::SocketAddr;
+use std::ops::Deref;
 use std::path::Path;
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+/// A secret, `Debug` doesn't show it so the config can be logged
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct Masked<T>(pub T);
+
+impl<T> Debug for Masked<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("MASKED")
+    }
+}
+
+impl<T> Deref for Masked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Default)]
 pub enum TransportType {
+    #[default]
     #[serde(rename = "tcp")]
     Tcp,
+    #[serde(rename = "tls")]
+    Tls,
+    #[serde(rename = "websocket")]
+    Websocket,
 }
 
-impl Default for TransportType {
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct TlsConfig {
+    /// Client only, the name to verify the server certificate against.
+    /// Defaults to the host part of `remote_addr`
+    pub hostname: Option<String>,
+    /// Client only, PEM file of the CA that signed the server certificate
+    pub trusted_root: Option<String>,
+    /// Server only, PEM file of the certificate chain
+    pub cert: Option<String>,
+    /// Server only, PEM file of the private key
+    pub key: Option<String>,
+}
+
+fn default_websocket_path() -> String {
+    String::from("/")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebsocketConfig {
+    /// Speak WebSocket over TLS with the settings of `transport.tls`
+    #[serde(default)]
+    pub tls: bool,
+    /// The path requested in the upgrade, the server refuses any other with 404
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+}
+
+impl Default for WebsocketConfig {
     fn default() -> Self {
-        TransportType::Tcp
+        WebsocketConfig {
+            tls: false,
+            path: default_websocket_path(),
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct TransportConfig {
+    #[serde(rename = "type", default = "TransportType::default")]
+    pub transport_type: TransportType,
+    pub tls: Option<TlsConfig>,
+    pub websocket: Option<WebsocketConfig>,
+    /// `socks5://[user:pass@]host:port` or `http://[user:pass@]host:port` the client
+    /// reaches the server through
+    pub proxy: Option<Masked<Url>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Default)]
 pub enum ServiceType {
+    #[default]
     #[serde(rename = "tcp")]
     Tcp,
+    #[serde(rename = "udp")]
+    Udp,
 }
 
-impl Default for ServiceType {
-    fn default() -> Self {
-        ServiceType::Tcp
-    }
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub enum ProxyProtocol {
+    #[serde(rename = "v1")]
+    V1,
+    #[serde(rename = "v2")]
+    V2,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
@@ -36,9 +111,19 @@ pub struct ClientServiceConfig {
     #[serde(skip)]
     pub name: String,
     pub local_addr: String,
-    pub token: Option<String>,
+    pub token: Option<Masked<String>>,
+    /// Prepend a PROXY protocol header carrying the visitor address when connecting
+    /// to `local_addr`. Tcp only
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Idle data channels kept open for visitors to start on right away, at most
+    /// `MAX_POOL_SIZE`. Tcp only, ignored when multiplexing
+    #[serde(default)]
+    pub pool_size: usize,
 }
 
+/// Every pooled data channel is a connection the server holds open
+pub const MAX_POOL_SIZE: usize = 64;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
 pub struct ServiceConfig {
     #[serde(rename = "type", default = "ServiceType::default")]
@@ -46,26 +131,134 @@ pub struct ServiceConfig {
     #[serde(skip)]
     pub name: String,
     pub bind_addrs: Vec<String>,
-    pub token: Option<String>,
+    pub token: Option<Masked<String>>,
+    /// Only visitors in these networks are accepted, everyone if empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Visitors in these networks are rejected, even if they are allowed
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Visitors connected at the same time. Tcp only
+    pub max_connections: Option<usize>,
+    /// New visitors accepted per second. Tcp only
+    pub max_connections_per_sec: Option<u32>,
+    /// Bytes per second from visitors to the service
+    pub inbound_limit: Option<u64>,
+    /// Bytes per second from the service to visitors
+    pub outbound_limit: Option<u64>,
+    /// Replaced by `[[server.hooks]]`, only read to refuse configs still setting it
+    #[serde(default, skip_serializing)]
     pub xz_notify: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum HookEvent {
+    /// `{service}` started listening on `{port}`
+    #[serde(rename = "service_bound")]
+    ServiceBound,
+    /// the client at `{addr}` established the control channel of `{service}`
+    #[serde(rename = "control_channel_up")]
+    ControlChannelUp,
+    #[serde(rename = "control_channel_down")]
+    ControlChannelDown,
+    /// the client at `{addr}` presented a wrong token for `{service}`
+    #[serde(rename = "auth_failed")]
+    AuthFailed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Default)]
+pub enum HttpMethod {
+    #[default]
+    #[serde(rename = "GET")]
+    Get,
+    #[serde(rename = "POST")]
+    Post,
+}
+
+/// Either an HTTP request to `url` or a local `command`. `{event}`, `{service}`, `{port}`
+/// and `{addr}` in the url, the body and the arguments are replaced with the event's values,
+/// percent-encoded in the url
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct HookConfig {
+    /// Events triggering the hook, all of them if empty
+    #[serde(default)]
+    pub events: Vec<HookEvent>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub method: HttpMethod,
+    /// Sent as `application/json` with `POST`
+    pub body: Option<String>,
+    /// The program and its arguments
+    pub command: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
+pub struct AdminConfig {
+    /// Serves `/status` as JSON and `/metrics` for Prometheus, keep it private
+    pub bind_addr: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
 pub struct ClientConfig {
     pub remote_addr: String,
-    pub default_token: Option<String>,
+    pub default_token: Option<Masked<String>>,
     pub services: HashMap<String, ClientServiceConfig>,
-    #[serde(default = "TransportType::default")]
-    pub transport: TransportType,
+    #[serde(default)]
+    pub transport: TransportConfig,
+    /// Seconds without any command from the server before the control channel
+    /// is considered dead and reconnected. 0 disables the check
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+    pub admin: Option<AdminConfig>,
+    /// Carry the visitors of a service as streams over a few long-lived connections
+    /// instead of a new connection each
+    pub multiplex: Option<MultiplexConfig>,
+}
+
+fn default_mux_connections() -> usize {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct MultiplexConfig {
+    /// Connections per service the streams are spread over
+    #[serde(default = "default_mux_connections")]
+    pub connections: usize,
+}
+
+impl Default for MultiplexConfig {
+    fn default() -> Self {
+        MultiplexConfig {
+            connections: default_mux_connections(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Clone)]
 pub struct ServerConfig {
     pub bind_addr: String,
-    pub default_token: Option<String>,
+    pub default_token: Option<Masked<String>>,
     pub services: HashMap<String, ServiceConfig>,
-    #[serde(default = "TransportType::default")]
-    pub transport: TransportType,
+    #[serde(default)]
+    pub transport: TransportConfig,
+    /// Seconds between heartbeats sent on every control channel. 0 disables heartbeats
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds without any heartbeat reply from the client before the control channel
+    /// is dropped. 0 disables the check
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_heartbeat_timeout() -> u64 {
+    40
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
@@ -77,47 +270,224 @@ pub struct Config {
 impl Config {
     fn from_str(s: &str) -> Result<Config> {
         let mut config: Config = toml::from_str(s).with_context(|| "Failed to parse config")?;
+        let mut errors = Vec::new();
 
         if let Some(server) = config.server.as_mut() {
-            Config::validate_server_config(server).unwrap();
+            Config::validate_server_config(server, &mut errors);
+            Config::validate_transport_config(&server.transport, true, &mut errors);
         }
 
         if let Some(client) = config.client.as_mut() {
-            Config::validate_client_config(client).unwrap();
+            Config::validate_client_config(client, &mut errors);
+            Config::validate_transport_config(&client.transport, false, &mut errors);
         }
 
         if config.server.is_none() && config.client.is_none() {
-            Err(anyhow!("Neither of `[server]` or `[client]` is defined"))
-        } else {
+            errors.push(String::from(
+                "Neither of `[server]` or `[client]` is defined",
+            ));
+        }
+
+        if errors.is_empty() {
             Ok(config)
+        } else {
+            Err(anyhow!(errors.join("\n")))
         }
     }
 
-    fn validate_server_config(server: &mut ServerConfig) -> Result<()> {
+    fn validate_server_config(server: &mut ServerConfig, errors: &mut Vec<String>) {
+        validate_addr("server.bind_addr", &server.bind_addr, errors);
+        if let Some(admin) = &server.admin {
+            validate_addr("server.admin.bind_addr", &admin.bind_addr, errors);
+        }
+        server.default_token = server
+            .default_token
+            .as_deref()
+            .map(|token| Masked(expand_token("server.default_token", token, errors)));
+
         for (name, s) in &mut server.services {
             s.name = name.clone();
+            let at = format!("server.services.{}", name);
+            validate_name(&at, name, errors);
+            if s.bind_addrs.is_empty() {
+                errors.push(format!(
+                    "{}.bind_addrs: at least one address is required",
+                    at
+                ));
+            }
+            for addr in &s.bind_addrs {
+                validate_addr(&format!("{}.bind_addrs", at), addr, errors);
+            }
+            if s.service_type == ServiceType::Udp
+                && (s.max_connections.is_some() || s.max_connections_per_sec.is_some())
+            {
+                errors.push(format!(
+                    "{}: `max_connections` and `max_connections_per_sec` are not supported for udp",
+                    at
+                ));
+            }
+            if s.inbound_limit == Some(0) || s.outbound_limit == Some(0) {
+                errors.push(format!(
+                    "{}: `inbound_limit` and `outbound_limit` must be positive",
+                    at
+                ));
+            }
+            if let Some(url) = &s.xz_notify {
+                errors.push(format!(
+                    "{}.xz_notify: replaced by `[[server.hooks]]`, use `events = [\"service_bound\"]` and `url = \"{}?title={{port}}\"`",
+                    at, url
+                ));
+            }
+            s.token = match s.token.as_deref() {
+                Some(token) => Some(Masked(expand_token(
+                    &format!("{}.token", at),
+                    token,
+                    errors,
+                ))),
+                None => server.default_token.clone(),
+            };
             if s.token.is_none() {
-                s.token = server.default_token.clone();
-                if s.token.is_none() {
-                    bail!("The token of service {} is not set", name)
-                }
+                errors.push(format!(
+                    "{}: the token is not set, set `token` or `server.default_token`",
+                    at
+                ));
             }
         }
 
-        Ok(())
+        if server.heartbeat_interval != 0
+            && server.heartbeat_timeout != 0
+            && server.heartbeat_timeout <= server.heartbeat_interval
+        {
+            errors.push(String::from(
+                "server: `heartbeat_timeout` must be longer than `heartbeat_interval`",
+            ));
+        }
+
+        for (i, hook) in server.hooks.iter().enumerate() {
+            match (&hook.url, &hook.command) {
+                (Some(_), None) => {}
+                (None, Some(command)) if !command.is_empty() => {}
+                _ => errors.push(format!(
+                    "server.hooks[{}]: a hook needs either `url` or a non-empty `command`",
+                    i
+                )),
+            }
+        }
     }
 
-    fn validate_client_config(client: &mut ClientConfig) -> Result<()> {
+    fn validate_client_config(client: &mut ClientConfig, errors: &mut Vec<String>) {
+        validate_addr("client.remote_addr", &client.remote_addr, errors);
+        if let Some(admin) = &client.admin {
+            validate_addr("client.admin.bind_addr", &admin.bind_addr, errors);
+        }
+        client.default_token = client
+            .default_token
+            .as_deref()
+            .map(|token| Masked(expand_token("client.default_token", token, errors)));
+
         for (name, s) in &mut client.services {
             s.name = name.clone();
+            let at = format!("client.services.{}", name);
+            validate_name(&at, name, errors);
+            validate_addr(&format!("{}.local_addr", at), &s.local_addr, errors);
+            if s.proxy_protocol.is_some() && s.service_type == ServiceType::Udp {
+                errors.push(format!("{}: `proxy_protocol` is not supported for udp", at));
+            }
+            if s.pool_size > 0 && s.service_type == ServiceType::Udp {
+                errors.push(format!("{}: `pool_size` is not supported for udp", at));
+            }
+            if s.pool_size > MAX_POOL_SIZE {
+                errors.push(format!(
+                    "{}.pool_size: must be at most {}",
+                    at, MAX_POOL_SIZE
+                ));
+            }
+            s.token = match s.token.as_deref() {
+                Some(token) => Some(Masked(expand_token(
+                    &format!("{}.token", at),
+                    token,
+                    errors,
+                ))),
+                None => client.default_token.clone(),
+            };
             if s.token.is_none() {
-                s.token = client.default_token.clone();
-                if s.token.is_none() {
-                    bail!("The token of service {} is not set", name)
+                errors.push(format!(
+                    "{}: the token is not set, set `token` or `client.default_token`",
+                    at
+                ));
+            }
+        }
+        if client
+            .multiplex
+            .as_ref()
+            .is_some_and(|m| m.connections == 0)
+        {
+            errors.push(String::from(
+                "client.multiplex.connections: must be positive",
+            ));
+        }
+    }
+
+    fn validate_transport_config(
+        config: &TransportConfig,
+        is_server: bool,
+        errors: &mut Vec<String>,
+    ) {
+        let at = if is_server {
+            "server.transport"
+        } else {
+            "client.transport"
+        };
+
+        if let Some(proxy) = &config.proxy {
+            if is_server {
+                errors.push(format!("{}.proxy: only supported by the client", at));
+            }
+            if !matches!(proxy.scheme(), "socks5" | "http") {
+                errors.push(format!(
+                    "{}.proxy: unsupported scheme `{}`, use socks5 or http",
+                    at,
+                    proxy.scheme()
+                ));
+            }
+            if proxy.host_str().is_none() {
+                errors.push(format!("{}.proxy: has no host", at));
+            }
+        }
+
+        match config.transport_type {
+            TransportType::Tcp => {}
+            TransportType::Tls => Config::validate_tls_config(config, at, is_server, errors),
+            TransportType::Websocket => {
+                let websocket = config.websocket.clone().unwrap_or_default();
+                if !websocket.path.starts_with('/') {
+                    errors.push(format!("{}.websocket.path: must start with `/`", at));
+                }
+                if websocket.tls {
+                    Config::validate_tls_config(config, at, is_server, errors);
                 }
             }
         }
-        Ok(())
+    }
+
+    fn validate_tls_config(
+        config: &TransportConfig,
+        at: &str,
+        is_server: bool,
+        errors: &mut Vec<String>,
+    ) {
+        let Some(tls) = config.tls.as_ref() else {
+            errors.push(format!("{}.tls: must be set for tls", at));
+            return;
+        };
+
+        if is_server {
+            if tls.cert.is_none() || tls.key.is_none() {
+                errors.push(format!("{}.tls: `cert` and `key` are required", at));
+            }
+        } else if tls.trusted_root.is_none() {
+            errors.push(format!("{}.tls: `trusted_root` is required", at));
+        }
     }
 
     pub fn from_file(path: &Path) -> Result<Config> {
@@ -130,6 +500,73 @@ impl Config {
     }
 }
 
+/// `token` with every `${NAME}` replaced by the environment variable `NAME`,
+/// so secrets need not be written in the file
+fn expand_token(at: &str, token: &str, errors: &mut Vec<String>) -> String {
+    let mut expanded = String::new();
+    let mut rest = token;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            errors.push(format!("{}: `${{` is not closed by `}}`", at));
+            return token.to_string();
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => errors.push(format!(
+                "{}: the environment variable `{}` is not set",
+                at, name
+            )),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+
+    if expanded.is_empty() {
+        errors.push(format!("{}: the token is empty", at));
+    }
+    expanded
+}
+
+/// Service names end up in logs, hook arguments and metric labels
+fn validate_name(at: &str, name: &str, errors: &mut Vec<String>) {
+    if name.is_empty() {
+        errors.push(format!("{}: the service name is empty", at));
+    } else if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        errors.push(format!(
+            "{}: the service name must not contain whitespace",
+            at
+        ));
+    }
+}
+
+/// `host:port`, where the host is a name, an ipv4 address or an ipv6 address
+/// in brackets
+fn validate_addr(at: &str, addr: &str, errors: &mut Vec<String>) {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return;
+    }
+    let problem = match addr.rsplit_once(':') {
+        None => "has no port, expected `host:port`",
+        Some((_, port)) if port.parse::<u16>().is_err() => "has an invalid port",
+        Some(("", _)) => "has no host, use `0.0.0.0` for every address",
+        Some((host, _)) if host.starts_with('[') => "has an invalid ipv6 address",
+        Some((host, _)) if host.contains(':') => {
+            "has an ipv6 address out of brackets, write it like `[::1]:2333`"
+        }
+        Some((host, _))
+            if !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_')) =>
+        {
+            "has an invalid host name"
+        }
+        Some(_) => return,
+    };
+    errors.push(format!("{}: `{}` {}", at, addr, problem));
+}
+
 #[cfg(test)]
 mod tests {
     use crate::config::Config;
@@ -146,4 +583,133 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_simple_server_config() -> Result<()> {
+        let path = PathBuf::from_str("tests/config_test/server.toml")?;
+        let s = fs::read_to_string(path)?;
+        println!("{:?}", Config::from_str(&s)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let err = Config::from_str(
+            r#"
+[server]
+bind_addr = "0.0.0.0"
+
+[server.services.ssh]
+bind_addrs = ["0.0.0.0:70000"]
+
+[server.services."my service"]
+token = "123"
+bind_addrs = ["::1:22"]
+"#,
+        )
+        .unwrap_err()
+        .to_string();
+
+        for expected in [
+            "server.bind_addr: `0.0.0.0` has no port",
+            "server.services.ssh.bind_addrs: `0.0.0.0:70000` has an invalid port",
+            "server.services.ssh: the token is not set",
+            "server.services.my service: the service name must not contain whitespace",
+            "server.services.my service.bind_addrs: `::1:22` has an ipv6 address out of brackets",
+        ] {
+            assert!(err.contains(expected), "{:?} not in {:?}", expected, err);
+        }
+        assert_eq!(err.lines().count(), 5);
+    }
+
+    #[test]
+    fn test_xz_notify_rejected() {
+        let err = Config::from_str(
+            r#"
+[server]
+bind_addr = "0.0.0.0:2333"
+default_token = "123"
+
+[server.services.ssh]
+bind_addrs = ["0.0.0.0:2222"]
+xz_notify = "http://example.com/notify"
+"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("server.services.ssh.xz_notify: replaced by `[[server.hooks]]`"));
+        assert!(err.contains("url = \"http://example.com/notify?title={port}\""));
+    }
+
+    #[test]
+    fn test_pool_size_limits() {
+        let err = Config::from_str(
+            r#"
+[client]
+remote_addr = "example.com:2333"
+default_token = "123"
+
+[client.services.dns]
+type = "udp"
+local_addr = "127.0.0.1:53"
+pool_size = 2
+
+[client.services.ssh]
+local_addr = "127.0.0.1:22"
+pool_size = 1000
+"#,
+        )
+        .unwrap_err()
+        .to_string();
+
+        for expected in [
+            "client.services.dns: `pool_size` is not supported for udp",
+            "client.services.ssh.pool_size: must be at most 64",
+        ] {
+            assert!(err.contains(expected), "{:?} not in {:?}", expected, err);
+        }
+        assert_eq!(err.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_token_from_env() -> Result<()> {
+        std::env::set_var("RATHOLE_TEST_TOKEN", "secret");
+        let config = Config::from_str(
+            r#"
+[client]
+remote_addr = "example.com:2333"
+default_token = "${RATHOLE_TEST_TOKEN}"
+
+[client.services.ssh]
+local_addr = "[::1]:22"
+
+[client.services.web]
+local_addr = "localhost:80"
+token = "prefix-${RATHOLE_TEST_TOKEN}"
+"#,
+        )?;
+        let client = config.client.unwrap();
+        let token = |name: &str| client.services[name].token.as_ref().map(|t| t.as_str());
+        assert_eq!(token("ssh"), Some("secret"));
+        assert_eq!(token("web"), Some("prefix-secret"));
+        // and the config can be logged without it
+        assert!(!format!("{:?}", client).contains("secret"));
+
+        let err = Config::from_str(
+            r#"
+[client]
+remote_addr = "example.com:2333"
+default_token = "${RATHOLE_TEST_UNSET}"
+
+[client.services.ssh]
+local_addr = "127.0.0.1:22"
+"#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("the environment variable `RATHOLE_TEST_UNSET` is not set"));
+        Ok(())
+    }
 }
@@ -0,0 +1,289 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, instrument};
+
+use crate::config::{ClientConfig, ClientServiceConfig, Config, ServerConfig, ServiceConfig};
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigChange {
+    /// Anything other than the services changed, the instance has to be restarted
+    General(Box<Config>),
+    ServerChange(ServerServiceChange),
+    ClientChange(ClientServiceChange),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServerServiceChange {
+    Add(ServiceConfig),
+    Delete(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClientServiceChange {
+    Add(ClientServiceConfig),
+    Delete(String),
+}
+
+/// Reloads the configuration whenever the file changes or on SIGHUP, and emits
+/// what changed compared to the last valid configuration
+pub struct ConfigWatcherHandle {
+    pub event_rx: mpsc::UnboundedReceiver<ConfigChange>,
+}
+
+impl ConfigWatcherHandle {
+    pub fn new(
+        path: &Path,
+        config: Config,
+        shutdown_rx: broadcast::Receiver<bool>,
+    ) -> Result<Self> {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
+
+        #[cfg(feature = "notify")]
+        let watcher = watch_file(path, reload_tx.clone())?;
+
+        #[cfg(unix)]
+        {
+            let mut sighup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            let reload_tx = reload_tx.clone();
+            tokio::spawn(async move {
+                while sighup.recv().await.is_some() {
+                    info!("Received SIGHUP");
+                    if reload_tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(reload_tx);
+
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            #[cfg(feature = "notify")]
+            let _watcher = watcher;
+            config_watcher(path, config, reload_rx, event_tx, shutdown_rx).await
+        });
+
+        Ok(ConfigWatcherHandle { event_rx })
+    }
+}
+
+/// Watch the parent directory, editors tend to replace the file rather than write to it
+#[cfg(feature = "notify")]
+fn watch_file(
+    path: &Path,
+    reload_tx: mpsc::UnboundedSender<()>,
+) -> Result<notify::RecommendedWatcher> {
+    use anyhow::Context;
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {:?}", path))?;
+    let file_name = path.file_name().map(|n| n.to_os_string());
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .to_path_buf();
+
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                let touched = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().map(|n| n.to_os_string()) == file_name);
+                if touched && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    let _ = reload_tx.send(());
+                }
+            }
+            Err(e) => error!("Config watcher error: {:?}", e),
+        })
+        .with_context(|| "Failed to create the config watcher")?;
+
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {:?}", dir))?;
+    Ok(watcher)
+}
+
+#[instrument(skip_all, fields(path = ?path))]
+async fn config_watcher(
+    path: PathBuf,
+    mut old: Config,
+    mut reload_rx: mpsc::UnboundedReceiver<()>,
+    event_tx: mpsc::UnboundedSender<ConfigChange>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            val = reload_rx.recv() => {
+                if val.is_none() {
+                    break;
+                }
+
+                let new = match Config::from_file(&path) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        // keep running the last valid configuration
+                        error!("Ignoring the modified configuration: {:?}", e);
+                        continue;
+                    }
+                };
+
+                for event in calculate_events(&old, &new) {
+                    info!("Configuration change detected: {:?}", event);
+                    if event_tx.send(event).is_err() {
+                        return;
+                    }
+                }
+                old = new;
+            },
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+}
+
+fn calculate_events(old: &Config, new: &Config) -> Vec<ConfigChange> {
+    if old == new {
+        return vec![];
+    }
+
+    let general_changed = old.server.as_ref().map(server_general)
+        != new.server.as_ref().map(server_general)
+        || old.client.as_ref().map(client_general) != new.client.as_ref().map(client_general);
+    if general_changed {
+        return vec![ConfigChange::General(Box::new(new.clone()))];
+    }
+
+    let mut events = vec![];
+    if let (Some(o), Some(n)) = (&old.server, &new.server) {
+        // a changed service is deleted first, which drops its control channel
+        for (name, s) in &o.services {
+            if n.services.get(name) != Some(s) {
+                events.push(ConfigChange::ServerChange(ServerServiceChange::Delete(
+                    name.clone(),
+                )));
+            }
+        }
+        for (name, s) in &n.services {
+            if o.services.get(name) != Some(s) {
+                events.push(ConfigChange::ServerChange(ServerServiceChange::Add(
+                    s.clone(),
+                )));
+            }
+        }
+    }
+    if let (Some(o), Some(n)) = (&old.client, &new.client) {
+        for (name, s) in &o.services {
+            if n.services.get(name) != Some(s) {
+                events.push(ConfigChange::ClientChange(ClientServiceChange::Delete(
+                    name.clone(),
+                )));
+            }
+        }
+        for (name, s) in &n.services {
+            if o.services.get(name) != Some(s) {
+                events.push(ConfigChange::ClientChange(ClientServiceChange::Add(
+                    s.clone(),
+                )));
+            }
+        }
+    }
+    events
+}
+
+fn server_general(config: &ServerConfig) -> ServerConfig {
+    ServerConfig {
+        services: Default::default(),
+        ..config.clone()
+    }
+}
+
+fn client_general(config: &ClientConfig) -> ClientConfig {
+    ClientConfig {
+        services: Default::default(),
+        ..config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Masked, ServiceType};
+
+    fn server_config(services: &[(&str, &str)]) -> Config {
+        let mut server = ServerConfig {
+            bind_addr: "0.0.0.0:2333".into(),
+            ..Default::default()
+        };
+        for (name, addr) in services {
+            server.services.insert(
+                name.to_string(),
+                ServiceConfig {
+                    name: name.to_string(),
+                    bind_addrs: vec![addr.to_string()],
+                    token: Some(Masked("123".into())),
+                    ..Default::default()
+                },
+            );
+        }
+        Config {
+            server: Some(server),
+            client: None,
+        }
+    }
+
+    #[test]
+    fn test_unchanged_config() {
+        let config = server_config(&[("foo", "0.0.0.0:80")]);
+        assert!(calculate_events(&config, &config.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_general_change() {
+        let old = server_config(&[("foo", "0.0.0.0:80")]);
+        let mut new = old.clone();
+        new.server.as_mut().unwrap().bind_addr = "0.0.0.0:2334".into();
+        assert_eq!(
+            calculate_events(&old, &new),
+            vec![ConfigChange::General(Box::new(new))]
+        );
+    }
+
+    #[test]
+    fn test_service_changes() {
+        let old = server_config(&[("foo", "0.0.0.0:80"), ("bar", "0.0.0.0:81")]);
+        let new = server_config(&[("foo", "0.0.0.0:80"), ("baz", "0.0.0.0:82")]);
+        let mut events = calculate_events(&old, &new);
+        assert_eq!(events.len(), 2);
+        assert!(
+            events.contains(&ConfigChange::ServerChange(ServerServiceChange::Delete(
+                "bar".into()
+            )))
+        );
+
+        let baz = new.server.as_ref().unwrap().services["baz"].clone();
+        assert!(events.contains(&ConfigChange::ServerChange(ServerServiceChange::Add(baz))));
+
+        // a modified service is restarted, delete comes first
+        let changed = {
+            let mut c = old.clone();
+            let foo = c.server.as_mut().unwrap().services.get_mut("foo").unwrap();
+            foo.service_type = ServiceType::Udp;
+            c
+        };
+        events = calculate_events(&old, &changed);
+        let foo = changed.server.as_ref().unwrap().services["foo"].clone();
+        assert_eq!(
+            events,
+            vec![
+                ConfigChange::ServerChange(ServerServiceChange::Delete("foo".into())),
+                ConfigChange::ServerChange(ServerServiceChange::Add(foo)),
+            ]
+        );
+    }
+}
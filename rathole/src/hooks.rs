@@ -0,0 +1,217 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::process::Command;
+use tokio::time;
+use tracing::{debug, error};
+
+use crate::config::{HookConfig, HookEvent, HttpMethod};
+
+/// A hook taking longer is abandoned
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything but the unreserved characters of RFC 3986 is encoded in a url
+const URL_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    ServiceBound { service: String, port: u16 },
+    ControlChannelUp { service: String, addr: SocketAddr },
+    ControlChannelDown { service: String },
+    AuthFailed { service: String, addr: SocketAddr },
+}
+
+impl Event {
+    fn kind(&self) -> HookEvent {
+        match self {
+            Event::ServiceBound { .. } => HookEvent::ServiceBound,
+            Event::ControlChannelUp { .. } => HookEvent::ControlChannelUp,
+            Event::ControlChannelDown { .. } => HookEvent::ControlChannelDown,
+            Event::AuthFailed { .. } => HookEvent::AuthFailed,
+        }
+    }
+
+    /// The values of the placeholders, the ones an event doesn't have are left empty
+    fn vars(&self) -> Vec<(&'static str, String)> {
+        let (name, service, port, addr) = match self {
+            Event::ServiceBound { service, port } => ("service_bound", service, Some(*port), None),
+            Event::ControlChannelUp { service, addr } => {
+                ("control_channel_up", service, None, Some(*addr))
+            }
+            Event::ControlChannelDown { service } => ("control_channel_down", service, None, None),
+            Event::AuthFailed { service, addr } => ("auth_failed", service, None, Some(*addr)),
+        };
+        vec![
+            ("event", name.to_string()),
+            ("service", service.clone()),
+            ("port", port.map(|p| p.to_string()).unwrap_or_default()),
+            ("addr", addr.map(|a| a.to_string()).unwrap_or_default()),
+        ]
+    }
+}
+
+/// Runs the `[[server.hooks]]` interested in an event. Every hook runs in the
+/// background, a slow or failing one never holds up the tunnel
+#[derive(Debug, Default)]
+pub struct Notifier {
+    hooks: Vec<HookConfig>,
+}
+
+impl Notifier {
+    pub fn new(hooks: Vec<HookConfig>) -> Self {
+        Notifier { hooks }
+    }
+
+    pub fn notify(&self, event: Event) {
+        let kind = event.kind();
+        for hook in &self.hooks {
+            if !hook.events.is_empty() && !hook.events.contains(&kind) {
+                continue;
+            }
+
+            let hook = hook.clone();
+            let vars = event.vars();
+            tokio::spawn(async move {
+                match run_hook(&hook, &vars).await {
+                    Ok(()) => debug!("Hook for {:?} done", kind),
+                    Err(e) => error!("Hook for {:?} failed: {:?}", kind, e),
+                }
+            });
+        }
+    }
+}
+
+async fn run_hook(hook: &HookConfig, vars: &[(&str, String)]) -> Result<()> {
+    if let Some(url) = &hook.url {
+        let encoded: Vec<_> = vars
+            .iter()
+            .map(|(name, value)| (*name, utf8_percent_encode(value, URL_VALUE).to_string()))
+            .collect();
+        let url = render(url, &encoded);
+        let body = hook.body.as_deref().map(|b| render(b, vars));
+        let method = hook.method;
+
+        // ureq blocks, keep it off the runtime
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let agent = ureq::AgentBuilder::new().timeout(HOOK_TIMEOUT).build();
+            match method {
+                HttpMethod::Get => agent.get(&url).call(),
+                HttpMethod::Post => agent
+                    .post(&url)
+                    .set("Content-Type", "application/json")
+                    .send_string(body.as_deref().unwrap_or("")),
+            }
+            .with_context(|| format!("Failed to request {}", url))?;
+            Ok(())
+        })
+        .await??;
+    } else if let Some(command) = &hook.command {
+        let args: Vec<String> = command.iter().map(|a| render(a, vars)).collect();
+        let status = time::timeout(
+            HOOK_TIMEOUT,
+            Command::new(&args[0])
+                .args(&args[1..])
+                .kill_on_drop(true)
+                .status(),
+        )
+        .await
+        .with_context(|| format!("`{}` timed out", args[0]))?
+        .with_context(|| format!("Failed to run `{}`", args[0]))?;
+
+        if !status.success() {
+            bail!("`{}` exited with {}", args[0], status);
+        }
+    }
+    Ok(())
+}
+
+fn render(template: &str, vars: &[(&str, String)]) -> String {
+    vars.iter().fold(template.to_string(), |s, (name, value)| {
+        s.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let event = Event::AuthFailed {
+            service: "ssh".into(),
+            addr: "1.2.3.4:5678".parse().unwrap(),
+        };
+        assert_eq!(
+            render(
+                "/notify?event={event}&service={service}&from={addr}&port={port}",
+                &event.vars()
+            ),
+            "/notify?event=auth_failed&service=ssh&from=1.2.3.4:5678&port="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_url_values_encoded() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let hook = HookConfig {
+            url: Some(format!(
+                "http://{}/notify?service={{service}}&from={{addr}}",
+                listener.local_addr()?
+            )),
+            ..Default::default()
+        };
+        let event = Event::AuthFailed {
+            service: "a&b=c".into(),
+            addr: "[::1]:5678".parse().unwrap(),
+        };
+        let request = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut conn, _) = listener.accept().await?;
+            let mut buf = vec![0u8; 1024];
+            let n = conn.read(&mut buf).await?;
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            anyhow::Ok(request.lines().next().unwrap_or("").to_string())
+        });
+        run_hook(&hook, &event.vars()).await?;
+        assert_eq!(
+            request.await??,
+            "GET /notify?service=a%26b%3Dc&from=%5B%3A%3A1%5D%3A5678 HTTP/1.1"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_command_hook() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out");
+        let hook = HookConfig {
+            command: Some(vec![
+                "sh".into(),
+                "-c".into(),
+                format!("echo {{service}} {{port}} > {}", out.display()),
+            ]),
+            ..Default::default()
+        };
+        let event = Event::ServiceBound {
+            service: "ssh".into(),
+            port: 2222,
+        };
+        run_hook(&hook, &event.vars()).await?;
+        assert_eq!(std::fs::read_to_string(out)?, "ssh 2222\n");
+
+        let failing = HookConfig {
+            command: Some(vec!["false".into()]),
+            ..Default::default()
+        };
+        assert!(run_hook(&failing, &event.vars()).await.is_err());
+        Ok(())
+    }
+}
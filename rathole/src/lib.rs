@@ -1,20 +1,30 @@
-use anyhow::Result;
-use tokio::sync::broadcast;
-use tracing::debug;
+use anyhow::{bail, Result};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info};
 
 pub use cli::Cli;
 
 use crate::client::run_client;
 use crate::config::Config;
+use crate::config_watcher::{ConfigChange, ConfigWatcherHandle};
 use crate::server::run_server;
 
+mod admin;
 mod cli;
 mod client;
 mod config;
+mod config_watcher;
 mod helper;
+mod hooks;
+mod mux;
 mod protocol;
+mod proxy_protocol;
 mod server;
+mod traffic;
 mod transport;
+mod visitor_filter;
+
+const UPDATE_CHAN_SIZE: usize = 32;
 
 #[derive(PartialEq, Eq, Debug)]
 enum RunMode {
@@ -33,33 +43,87 @@ pub async fn run(args: Cli, shutdown_tx: broadcast::Sender<bool>) -> Result<()>
 
     debug!("{:?}", config);
 
-    let join = tokio::spawn(run_instance(
-        config.clone(),
+    if args.check {
+        if determine_run_mode(&config, &args) == RunMode::Undetermined {
+            bail!(
+                "Cannot determine running as a server or a client, pass `--server` or `--client`"
+            );
+        }
+        println!("{} is valid", config_path.display());
+        return Ok(());
+    }
+
+    let mut cfg_watcher =
+        ConfigWatcherHandle::new(config_path, config.clone(), shutdown_tx.subscribe())?;
+
+    // every instance has its own shutdown channel so it can be restarted alone
+    let (mut instance_shutdown_tx, _) = broadcast::channel::<bool>(1);
+    let (mut update_tx, update_rx) = mpsc::channel(UPDATE_CHAN_SIZE);
+    let mut join = tokio::spawn(run_instance(
+        config,
         args.clone(),
-        shutdown_tx.subscribe(),
+        instance_shutdown_tx.subscribe(),
+        update_rx,
     ));
 
-    join.await?;
+    loop {
+        tokio::select! {
+            _ = &mut join => break,
+            event = cfg_watcher.event_rx.recv() => {
+                match event {
+                    Some(ConfigChange::General(config)) => {
+                        info!("General configuration changed, restarting the instance");
+                        let _ = instance_shutdown_tx.send(true);
+                        let _ = (&mut join).await;
+
+                        (instance_shutdown_tx, _) = broadcast::channel(1);
+                        let update_rx;
+                        (update_tx, update_rx) = mpsc::channel(UPDATE_CHAN_SIZE);
+                        join = tokio::spawn(run_instance(
+                            *config,
+                            args.clone(),
+                            instance_shutdown_tx.subscribe(),
+                            update_rx,
+                        ));
+                    }
+                    Some(event) => {
+                        let _ = update_tx.send(event).await;
+                    }
+                    // the watcher stops on shutdown
+                    None => {
+                        let _ = instance_shutdown_tx.send(true);
+                        let _ = join.await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
     let _ = shutdown_tx.send(true);
 
     Ok(())
 }
 
-async fn run_instance(config: Config, arg: Cli, shutdown_rx: broadcast::Receiver<bool>) {
+async fn run_instance(
+    config: Config,
+    arg: Cli,
+    shutdown_rx: broadcast::Receiver<bool>,
+    update_rx: mpsc::Receiver<ConfigChange>,
+) {
     let ret: Result<()> = match determine_run_mode(&config, &arg) {
         RunMode::Server => {
             #[cfg(not(feature = "server"))]
             helper::feature_not_compile("server");
 
             #[cfg(feature = "server")]
-            run_server(&config, shutdown_rx).await
+            run_server(&config, shutdown_rx, update_rx).await
         }
         RunMode::Client => {
             #[cfg(not(feature = "client"))]
             helper::feature_not_compile("client");
 
             #[cfg(feature = "client")]
-            run_client(&config, shutdown_rx).await
+            run_client(&config, shutdown_rx, update_rx).await
         }
         RunMode::Undetermined => panic!("Cannot determine running as a server or a client"),
     };
@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use anyhow::{bail, Context as _, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+/// Bytes a stream may have in flight before the receiver acknowledges them
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Also bounds what a peer can make us allocate for a single frame
+const MAX_DATA_FRAME: usize = 16 * 1024;
+const HEADER_LEN: usize = 9;
+
+// Frame kinds
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const WINDOW_UPDATE: u8 = 2;
+// the sender won't write anymore
+const CLOSE: u8 = 3;
+// the sender won't read or write anymore
+const RESET: u8 = 4;
+
+/// `[stream id: u32][kind: u8][value: u32]` followed by `value` bytes for `DATA`,
+/// all big endian. `value` is the window increment for `WINDOW_UPDATE`, zero otherwise
+#[derive(Debug, PartialEq)]
+struct Frame {
+    id: u32,
+    kind: u8,
+    value: u32,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    fn new(id: u32, kind: u8) -> Frame {
+        Frame {
+            id,
+            kind,
+            value: 0,
+            data: vec![],
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.value.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+
+    async fn read<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Frame> {
+        let mut header = [0u8; HEADER_LEN];
+        conn.read_exact(&mut header).await?;
+        let id = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let kind = header[4];
+        let value = u32::from_be_bytes(header[5..].try_into().unwrap());
+
+        let mut data = vec![];
+        if kind == DATA {
+            if value as usize > MAX_DATA_FRAME {
+                bail!("Data frame of {} bytes exceeds the limit", value);
+            }
+            data.resize(value as usize, 0);
+            conn.read_exact(&mut data).await?;
+        }
+        Ok(Frame {
+            id,
+            kind,
+            value,
+            data,
+        })
+    }
+}
+
+/// The side of the transport connection, to keep the ids of streams opened by
+/// either side apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    window: u32,
+    waker: Option<Waker>,
+    // the peer reset the stream or the session is gone
+    closed: bool,
+}
+
+impl SendState {
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Entry {
+    // dropped once the peer closes its side, which ends the reads
+    data_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    send: Arc<Mutex<SendState>>,
+    // what the peer may still send before we acknowledge more
+    recv_window: Arc<AtomicU32>,
+    // the reads ended without the peer closing its side
+    reset: Arc<AtomicBool>,
+}
+
+impl Entry {
+    /// End the stream both ways, reads fail unless the peer already closed its side
+    fn reset(self) {
+        if self.data_tx.is_some() {
+            self.reset.store(true, Ordering::Relaxed);
+        }
+        self.send.lock().unwrap().close();
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, Entry>>,
+    frame_tx: mpsc::UnboundedSender<Frame>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let send = Arc::new(Mutex::new(SendState {
+            window: INITIAL_WINDOW,
+            ..Default::default()
+        }));
+        let recv_window = Arc::new(AtomicU32::new(INITIAL_WINDOW));
+        let reset = Arc::new(AtomicBool::new(false));
+        self.streams.lock().unwrap().insert(
+            id,
+            Entry {
+                data_tx: Some(data_tx),
+                send: send.clone(),
+                recv_window: recv_window.clone(),
+                reset: reset.clone(),
+            },
+        );
+        MuxStream {
+            id,
+            shared: self.clone(),
+            data_rx,
+            buf: vec![],
+            pos: 0,
+            unacked: 0,
+            recv_window,
+            reset,
+            send,
+            fin_sent: false,
+        }
+    }
+
+    /// Fail every stream, the transport connection is gone
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.reset();
+        }
+    }
+}
+
+/// Opens streams over a multiplexed transport connection, cheap to clone
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+    next_id: Arc<AtomicU32>,
+}
+
+impl Session {
+    pub fn open(&self) -> Result<MuxStream> {
+        if self.is_closed() {
+            bail!("The multiplexed connection is closed");
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.register(id);
+        self.shared
+            .frame_tx
+            .send(Frame::new(id, OPEN))
+            .with_context(|| "The multiplexed connection is closed")?;
+        Ok(stream)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Multiplex streams over `conn`. Returns the session to open streams with, the
+/// streams opened by the peer and the future driving the connection. Dropping the
+/// future closes the connection and fails all of its streams
+pub fn session<T>(
+    conn: T,
+    side: Side,
+) -> (
+    Session,
+    mpsc::UnboundedReceiver<MuxStream>,
+    impl Future<Output = Result<()>>,
+)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (frame_tx, frame_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        streams: Mutex::new(HashMap::new()),
+        frame_tx,
+        closed: AtomicBool::new(false),
+    });
+    let session = Session {
+        shared: shared.clone(),
+        next_id: Arc::new(AtomicU32::new(match side {
+            Side::Client => 1,
+            Side::Server => 2,
+        })),
+    };
+
+    let driver = async move {
+        let _guard = CloseOnDrop(shared.clone());
+        let (mut rd, mut wr) = tokio::io::split(conn);
+        // the writer only stops on errors, the session keeps a sender
+        tokio::select! {
+            res = read_frames(&mut rd, &shared, incoming_tx) => res,
+            res = write_frames(&mut wr, frame_rx) => res,
+        }
+    };
+    (session, incoming_rx, driver)
+}
+
+struct CloseOnDrop(Arc<Shared>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+async fn read_frames<T: AsyncRead + Unpin>(
+    rd: &mut T,
+    shared: &Arc<Shared>,
+    incoming_tx: mpsc::UnboundedSender<MuxStream>,
+) -> Result<()> {
+    loop {
+        let frame = match Frame::read(rd).await {
+            Ok(frame) => frame,
+            Err(e) => match e.downcast_ref::<io::Error>() {
+                Some(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                _ => return Err(e).with_context(|| "Failed to read a mux frame"),
+            },
+        };
+
+        match frame.kind {
+            OPEN => {
+                if shared.streams.lock().unwrap().contains_key(&frame.id) {
+                    bail!("Stream {} opened twice", frame.id);
+                }
+                // dropped right away and reset if nobody accepts streams
+                let _ = incoming_tx.send(shared.register(frame.id));
+            }
+            DATA => {
+                let mut streams = shared.streams.lock().unwrap();
+                let Some(entry) = streams.get_mut(&frame.id) else {
+                    continue;
+                };
+                let len = frame.data.len() as u32;
+                let within_window = entry
+                    .recv_window
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| w.checked_sub(len))
+                    .is_ok();
+                if !within_window {
+                    bail!("Stream {} exceeded its window", frame.id);
+                }
+                if let Some(tx) = &entry.data_tx {
+                    let _ = tx.send(frame.data);
+                }
+            }
+            WINDOW_UPDATE => {
+                if let Some(entry) = shared.streams.lock().unwrap().get(&frame.id) {
+                    let mut send = entry.send.lock().unwrap();
+                    send.window = send.window.saturating_add(frame.value);
+                    if let Some(waker) = send.waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            CLOSE => {
+                if let Some(entry) = shared.streams.lock().unwrap().get_mut(&frame.id) {
+                    entry.data_tx = None;
+                }
+            }
+            RESET => {
+                if let Some(entry) = shared.streams.lock().unwrap().remove(&frame.id) {
+                    entry.reset();
+                }
+            }
+            kind => bail!("Unknown mux frame kind {}", kind),
+        }
+    }
+}
+
+async fn write_frames<T: AsyncWrite + Unpin>(
+    wr: &mut T,
+    mut frame_rx: mpsc::UnboundedReceiver<Frame>,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    while let Some(frame) = frame_rx.recv().await {
+        // batch whatever else is queued into a single write
+        buf.clear();
+        frame.encode(&mut buf);
+        while let Ok(frame) = frame_rx.try_recv() {
+            frame.encode(&mut buf);
+        }
+        wr.write_all(&buf)
+            .await
+            .with_context(|| "Failed to write mux frames")?;
+        wr.flush().await?;
+    }
+    Ok(())
+}
+
+/// A logical stream of a `Session`
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    // the chunk being read
+    buf: Vec<u8>,
+    pos: usize,
+    // read but not acknowledged to the peer yet
+    unacked: u32,
+    recv_window: Arc<AtomicU32>,
+    // set before the reads end if the peer never closed its side
+    reset: Arc<AtomicBool>,
+    send: Arc<Mutex<SendState>>,
+    fin_sent: bool,
+}
+
+impl std::fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream").field("id", &self.id).finish()
+    }
+}
+
+impl MuxStream {
+    fn send_frame(&self, frame: Frame) -> io::Result<()> {
+        self.shared
+            .frame_tx
+            .send(frame)
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = buf.remaining().min(self.buf.len() - self.pos);
+                let pos = self.pos;
+                buf.put_slice(&self.buf[pos..pos + n]);
+                self.pos += n;
+                self.unacked += n as u32;
+
+                // acknowledge in batches, not for every read
+                if self.unacked >= INITIAL_WINDOW / 2 {
+                    let value = std::mem::take(&mut self.unacked);
+                    self.recv_window.fetch_add(value, Ordering::Relaxed);
+                    let mut frame = Frame::new(self.id, WINDOW_UPDATE);
+                    frame.value = value;
+                    // a dead session already ends the read below
+                    let _ = self.send_frame(frame);
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                // the peer closed, or reset the stream or the session is gone
+                Poll::Ready(None) if self.reset.load(Ordering::Relaxed) => {
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if self.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = {
+            let mut send = self.send.lock().unwrap();
+            if send.closed {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if send.window == 0 {
+                send.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(send.window as usize).min(MAX_DATA_FRAME);
+            send.window -= n as u32;
+            n
+        };
+
+        let mut frame = Frame::new(self.id, DATA);
+        frame.value = n as u32;
+        frame.data = buf[..n].to_vec();
+        self.send_frame(frame)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the session writes out frames as soon as they are queued
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.fin_sent {
+            self.fin_sent = true;
+            self.send_frame(Frame::new(self.id, CLOSE))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let known = self
+            .shared
+            .streams
+            .lock()
+            .unwrap()
+            .remove(&self.id)
+            .is_some();
+        if known {
+            let _ = self.send_frame(Frame::new(self.id, RESET));
+        }
+    }
+}
+
+/// A data channel, either a connection of its own or a stream of a multiplexed one
+#[derive(Debug)]
+pub enum DataChannel<S> {
+    Direct(S),
+    Muxed(MuxStream),
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DataChannel<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_read(cx, buf),
+            DataChannel::Muxed(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DataChannel<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_write(cx, buf),
+            DataChannel::Muxed(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_flush(cx),
+            DataChannel::Muxed(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DataChannel::Direct(s) => Pin::new(s).poll_shutdown(cx),
+            DataChannel::Muxed(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (
+        (Session, mpsc::UnboundedReceiver<MuxStream>),
+        (Session, mpsc::UnboundedReceiver<MuxStream>),
+    ) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (client, client_incoming, client_driver) = session(a, Side::Client);
+        let (server, server_incoming, server_driver) = session(b, Side::Server);
+        tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+        ((client, client_incoming), (server, server_incoming))
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let mut frame = Frame::new(3, DATA);
+        frame.value = 2;
+        frame.data = b"hi".to_vec();
+        let mut buf = vec![];
+        frame.encode(&mut buf);
+        assert_eq!(buf, [0, 0, 0, 3, DATA, 0, 0, 0, 2, b'h', b'i']);
+    }
+
+    #[tokio::test]
+    async fn test_streams_are_independent() -> Result<()> {
+        let ((_client, mut incoming), (server, _)) = pair();
+
+        let mut s1 = server.open()?;
+        let mut s2 = server.open()?;
+        s2.write_all(b"second").await?;
+        s1.write_all(b"first").await?;
+        s1.shutdown().await?;
+
+        let mut c1 = incoming.recv().await.unwrap();
+        let mut c2 = incoming.recv().await.unwrap();
+        let mut buf = vec![];
+        c1.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"first");
+        let mut buf = [0u8; 6];
+        c2.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"second");
+
+        // the other direction still works after the first one is closed
+        c1.write_all(b"reply").await?;
+        c1.shutdown().await?;
+        drop(c1);
+        let mut buf = vec![];
+        s1.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"reply");
+        assert!(s1.write_all(b"too late").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() -> Result<()> {
+        let ((_client, mut incoming), (server, _)) = pair();
+
+        let mut sender = server.open()?;
+        let data = vec![7u8; INITIAL_WINDOW as usize * 3];
+        let expected = data.clone();
+        let write = tokio::spawn(async move {
+            sender.write_all(&data).await?;
+            sender.shutdown().await?;
+            Ok::<_, io::Error>(sender)
+        });
+
+        let mut receiver = incoming.recv().await.unwrap();
+        // a writer can't get further than the window ahead of the reader
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!write.is_finished());
+        assert!(receiver.recv_window.load(Ordering::Relaxed) < INITIAL_WINDOW / 2);
+
+        let mut buf = vec![];
+        receiver.read_to_end(&mut buf).await?;
+        assert_eq!(buf, expected);
+        write.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams_fail_with_the_connection() -> Result<()> {
+        let (a, b) = tokio::io::duplex(1024);
+        let (client, mut incoming, client_driver) = session(a, Side::Client);
+        let (server, _, server_driver) = session(b, Side::Server);
+        let client_driver = tokio::spawn(client_driver);
+        tokio::spawn(server_driver);
+
+        let mut s = server.open()?;
+        s.write_all(b"x").await?;
+        let mut c = incoming.recv().await.unwrap();
+        let mut buf = [0u8; 1];
+        c.read_exact(&mut buf).await?;
+
+        client_driver.abort();
+        let _ = client_driver.await;
+        assert!(client.is_closed());
+        assert!(client.open().is_err());
+        let err = c.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = c.write_all(b"x").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // the other end of the connection is gone as well
+        let mut buf = vec![];
+        let err = s.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        Ok(())
+    }
+}
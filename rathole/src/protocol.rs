@@ -1,10 +1,15 @@
-use anyhow::{Context, Result};
-use lazy_static::lazy_static;
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub const HASH_WIDTH_IN_BYTES: usize = 32;
 
+/// Big enough for any udp datagram
+pub const UDP_BUFFER_SIZE: usize = u16::MAX as usize;
+
 pub type Digest = [u8; HASH_WIDTH_IN_BYTES];
 
 pub fn digest(data: &[u8]) -> Digest {
@@ -13,21 +18,118 @@ pub fn digest(data: &[u8]) -> Digest {
     d.into()
 }
 
+/// Compare digests in constant time, they are secrets a peer tries to guess
+pub fn digest_eq(a: &Digest, b: &Digest) -> bool {
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The key data channels of a control channel must present.
+/// Derived from the token and the fresh nonce of the control channel handshake,
+/// so it can't be guessed from the service name and dies with the control channel
+pub fn data_channel_key(token: &str, nonce: &Digest) -> Digest {
+    let mut concat = Vec::from(b"data channel:".as_slice());
+    concat.extend_from_slice(token.as_bytes());
+    concat.extend_from_slice(nonce);
+    digest(&concat)
+}
+
+/// Bumped on every incompatible change of the messages below
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional features of the protocol, only used if both peers announce them
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// The client answers `ControlChannelCmd::Heartbeat` with `Hello::Heartbeat`
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 0);
+    /// The server takes `Hello::MuxChannel` and opens data channels as streams on it
+    pub const MULTIPLEX: Capabilities = Capabilities(1 << 1);
+
+    /// Everything this build supports
+    pub const fn supported() -> Capabilities {
+        Capabilities(Capabilities::HEARTBEAT.0 | Capabilities::MULTIPLEX.0)
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Sent by both sides of a control channel before anything else
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Handshake {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    pub fn local() -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+        }
+    }
+
+    /// The version and capabilities both peers speak. Symmetric, so the two sides
+    /// always come to the same conclusion
+    pub fn negotiate(&self, remote: &Handshake) -> Result<Handshake> {
+        let version = self.version.min(remote.version);
+        if version < self.min_version.max(remote.min_version) {
+            bail!(
+                "Incompatible protocol versions: this side speaks {}..={}, the peer speaks {}..={}. \
+                Upgrade the older of the server and the client",
+                self.min_version,
+                self.version,
+                remote.min_version,
+                remote.version
+            );
+        }
+        Ok(Handshake {
+            version,
+            min_version: version,
+            capabilities: self.capabilities.intersection(remote.capabilities),
+        })
+    }
+}
+
+/// The first message of every connection. The layout of `ControlChannel` must never
+/// change, peers of any version rely on it to tell each other their versions
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub enum Hello {
-    ControlChannel(Digest),
+    /// The client sends the digest of the service name, the server replies with a nonce
+    ControlChannel(Handshake, Digest),
     DataChannel(Digest),
     ControlChannelClose,
+    /// The client's reply to `ControlChannelCmd::Heartbeat`
+    Heartbeat,
+    /// A connection to multiplex the data channels of a control channel over,
+    /// only sent if both sides have `Capabilities::MULTIPLEX`
+    MuxChannel(Digest),
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum ControlChannelCmd {
     CreateDataChannel,
+    Heartbeat,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub enum DataChannelCmd {
-    StartForwardTcp,
+    /// `visitor` connected to the service listening at `bind`, both go into
+    /// the PROXY protocol header
+    StartForwardTcp {
+        visitor: SocketAddr,
+        bind: SocketAddr,
+    },
     StartForwardUdp,
 }
 
@@ -41,88 +143,265 @@ pub enum Ack {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Auth(pub Digest);
 
-///直接固定每个指令的长度，如此简单粗暴?
-struct PacketLength {
-    hello: usize,
-    ack: usize,
-    auth: usize,
-    c_cmd: usize,
-    d_cmd: usize,
+#[derive(Deserialize, Serialize, Debug)]
+struct UdpHeader {
+    from: SocketAddr,
+    len: u16,
 }
 
-impl PacketLength {
-    pub fn new() -> Self {
-        let username = "default";
-        let d = digest(username.as_bytes());
-        let hello = bincode::serialized_size(&Hello::ControlChannel(d)).unwrap() as usize;
+/// A udp datagram carried over a data channel, framed as
+/// `[header length: u8][UdpHeader][data]`, the header size differs between v4 and v6
+#[derive(Debug, PartialEq)]
+pub struct UdpTraffic {
+    /// the visitor who sent or will receive the datagram
+    pub from: SocketAddr,
+    pub data: Vec<u8>,
+}
 
-        let ack = Ack::Ok;
-        let ack = bincode::serialized_size(&ack).unwrap() as usize;
+impl UdpTraffic {
+    pub async fn write<T: AsyncWrite + Unpin>(&self, conn: &mut T) -> Result<()> {
+        UdpTraffic::write_slice(conn, self.from, &self.data).await
+    }
 
-        let auth = bincode::serialized_size(&Auth(d)).unwrap() as usize;
+    pub async fn write_slice<T: AsyncWrite + Unpin>(
+        conn: &mut T,
+        from: SocketAddr,
+        data: &[u8],
+    ) -> Result<()> {
+        let header = UdpHeader {
+            from,
+            len: data.len() as u16,
+        };
+        let header = bincode::serialize(&header).unwrap();
 
-        let c_cmd =
-            bincode::serialized_size(&ControlChannelCmd::CreateDataChannel).unwrap() as usize;
-        let d_cmd = bincode::serialized_size(&DataChannelCmd::StartForwardTcp).unwrap() as usize;
+        let mut buf = Vec::with_capacity(1 + header.len() + data.len());
+        buf.push(header.len() as u8);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(data);
+        conn.write_all(&buf)
+            .await
+            .with_context(|| "Failed to write udp traffic")
+    }
 
-        Self {
-            hello,
-            ack,
-            auth,
-            c_cmd,
-            d_cmd,
-        }
+    pub async fn read<T: AsyncRead + Unpin>(conn: &mut T) -> Result<UdpTraffic> {
+        let len = conn
+            .read_u8()
+            .await
+            .with_context(|| "Failed to read udp header length")?;
+
+        let mut buf = vec![0u8; len as usize];
+        conn.read_exact(&mut buf)
+            .await
+            .with_context(|| "Failed to read udp header")?;
+        let header: UdpHeader =
+            bincode::deserialize(&buf).with_context(|| "Failed to deserialize udp header")?;
+
+        let mut data = vec![0u8; header.len as usize];
+        conn.read_exact(&mut data)
+            .await
+            .with_context(|| "Failed to read udp data")?;
+
+        Ok(UdpTraffic {
+            from: header.from,
+            data,
+        })
     }
 }
 
-lazy_static! {
-    static ref PACK_LEN: PacketLength = PacketLength::new();
+/// Frames bigger than this are a broken or hostile peer, the messages are tiny
+const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+/// Write a message framed as `[payload length: u32 big endian][bincode payload]`
+pub async fn write_message<T, M>(conn: &mut T, msg: &M) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+    M: Serialize + ?Sized,
+{
+    let payload = bincode::serialize(msg).with_context(|| "Failed to serialize message")?;
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    conn.write_all(&buf)
+        .await
+        .with_context(|| "Failed to write message")
 }
 
-pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Hello> {
-    let mut buf = vec![0u8; PACK_LEN.hello];
+/// Read the payload of one frame. The frame is consumed as a whole, so a message
+/// that fails to decode leaves the stream at the start of the next one
+pub async fn read_frame<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Vec<u8>> {
+    let len = conn
+        .read_u32()
+        .await
+        .with_context(|| "Failed to read frame length")?;
+    if len > MAX_FRAME_SIZE {
+        bail!(
+            "Frame of {} bytes exceeds the limit of {}",
+            len,
+            MAX_FRAME_SIZE
+        );
+    }
+
+    let mut buf = vec![0u8; len as usize];
     conn.read_exact(&mut buf)
         .await
-        .with_context(|| "Failed to read hello")?;
+        .with_context(|| "Failed to read frame")?;
+    Ok(buf)
+}
 
-    let hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
-    Ok(hello)
+pub fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M> {
+    bincode::deserialize(frame).with_context(|| {
+        format!(
+            "Failed to deserialize {}",
+            std::any::type_name::<M>().rsplit("::").next().unwrap()
+        )
+    })
 }
 
-pub async fn read_auth<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Auth> {
-    let mut buf = vec![0u8; PACK_LEN.auth];
-    conn.read_exact(&mut buf)
-        .await
-        .with_context(|| "Failed to read auth")?;
-    bincode::deserialize(&buf).with_context(|| "Failed to deserialize auth")
+pub async fn read_message<T: AsyncRead + Unpin, M: DeserializeOwned>(conn: &mut T) -> Result<M> {
+    decode(&read_frame(conn).await?)
 }
 
-pub async fn read_ack<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Ack> {
-    let mut bytes = vec![0u8; PACK_LEN.ack];
-    conn.read_exact(&mut bytes)
-        .await
-        .with_context(|| "Failed to read ack")?;
+pub async fn read_hello<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Hello> {
+    read_message(conn).await
+}
 
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize ack")
+pub async fn read_auth<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Auth> {
+    read_message(conn).await
 }
 
-pub async fn read_control_cmd<T: AsyncRead + AsyncWrite + Unpin>(
-    conn: &mut T,
-) -> Result<ControlChannelCmd> {
-    let mut bytes = vec![0u8; PACK_LEN.c_cmd];
+pub async fn read_ack<T: AsyncRead + Unpin>(conn: &mut T) -> Result<Ack> {
+    read_message(conn).await
+}
 
-    conn.read_exact(&mut bytes)
-        .await
-        .with_context(|| "Failed to read control cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize control cmd")
+pub async fn read_data_cmd<T: AsyncRead + Unpin>(conn: &mut T) -> Result<DataChannelCmd> {
+    read_message(conn).await
 }
 
-pub async fn read_data_cmd<T: AsyncRead + AsyncWrite + Unpin>(
-    conn: &mut T,
-) -> Result<DataChannelCmd> {
-    let mut bytes = vec![0u8; PACK_LEN.d_cmd];
-    conn.read_exact(&mut bytes)
-        .await
-        .with_context(|| "Failed to read data cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize")
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{
+        decode, digest, digest_eq, read_data_cmd, read_frame, read_hello, write_message,
+        Capabilities, ControlChannelCmd, DataChannelCmd, Handshake, Hello, UdpTraffic,
+    };
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn test_udp_traffic_framing() -> Result<()> {
+        let v4 = UdpTraffic {
+            from: "127.0.0.1:5353".parse()?,
+            data: b"hello".to_vec(),
+        };
+        let v6 = UdpTraffic {
+            from: "[::1]:53".parse()?,
+            data: vec![],
+        };
+
+        let mut buf = vec![];
+        v4.write(&mut buf).await?;
+        v6.write(&mut buf).await?;
+
+        let mut rd = buf.as_slice();
+        assert_eq!(UdpTraffic::read(&mut rd).await?, v4);
+        assert_eq!(UdpTraffic::read(&mut rd).await?, v6);
+        assert!(rd.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_framing() -> Result<()> {
+        let v4 = DataChannelCmd::StartForwardTcp {
+            visitor: "1.2.3.4:5678".parse()?,
+            bind: "0.0.0.0:80".parse()?,
+        };
+        let v6 = DataChannelCmd::StartForwardTcp {
+            visitor: "[2001:db8::1]:5678".parse()?,
+            bind: "[::]:80".parse()?,
+        };
+
+        let mut buf = vec![];
+        write_message(&mut buf, &Hello::Heartbeat).await?;
+        write_message(&mut buf, &v4).await?;
+        write_message(&mut buf, &DataChannelCmd::StartForwardUdp).await?;
+        write_message(&mut buf, &v6).await?;
+
+        let mut rd = buf.as_slice();
+        assert_eq!(read_hello(&mut rd).await?, Hello::Heartbeat);
+        assert_eq!(read_data_cmd(&mut rd).await?, v4);
+        assert_eq!(
+            read_data_cmd(&mut rd).await?,
+            DataChannelCmd::StartForwardUdp
+        );
+        assert_eq!(read_data_cmd(&mut rd).await?, v6);
+        assert!(rd.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_is_skipped() -> Result<()> {
+        // a control command from a newer peer, variant 99 doesn't exist here
+        let mut buf = vec![];
+        write_message(&mut buf, &99u32).await?;
+        write_message(&mut buf, &ControlChannelCmd::Heartbeat).await?;
+
+        let mut rd = buf.as_slice();
+        assert!(decode::<ControlChannelCmd>(&read_frame(&mut rd).await?).is_err());
+        assert!(matches!(
+            decode(&read_frame(&mut rd).await?)?,
+            ControlChannelCmd::Heartbeat
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let buf = u32::MAX.to_be_bytes();
+        assert!(read_frame(&mut buf.as_slice()).await.is_err());
+    }
+
+    fn handshake(min_version: u16, version: u16, capabilities: u32) -> Handshake {
+        Handshake {
+            version,
+            min_version,
+            capabilities: Capabilities(capabilities),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_mixed_versions() -> Result<()> {
+        let old = handshake(1, 1, 0b01);
+        let new = handshake(1, 3, 0b11);
+
+        // the newer side falls back to the older one, from either end
+        assert_eq!(old.negotiate(&new)?, handshake(1, 1, 0b01));
+        assert_eq!(new.negotiate(&old)?, handshake(1, 1, 0b01));
+
+        let newer = handshake(2, 3, 0b11);
+        let err = old.negotiate(&newer).unwrap_err().to_string();
+        assert!(err.contains("this side speaks 1..=1, the peer speaks 2..=3"));
+        assert!(newer.negotiate(&old).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hello_control_channel_layout() -> Result<()> {
+        // what every version must be able to parse: variant 0, versions, capabilities
+        let hello = Hello::ControlChannel(handshake(1, 2, 1), [7u8; 32]);
+        let buf = bincode::serialize(&hello)?;
+        assert_eq!(&buf[..12], &[0, 0, 0, 0, 2, 0, 1, 0, 1, 0, 0, 0]);
+        assert_eq!(buf.len(), 12 + 32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_eq() {
+        let key = digest(b"key");
+        assert!(digest_eq(&key, &digest(b"key")));
+        let mut other = key;
+        other[31] ^= 1;
+        assert!(!digest_eq(&key, &other));
+    }
 }
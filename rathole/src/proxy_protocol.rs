@@ -0,0 +1,119 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::ProxyProtocol;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Build the PROXY protocol header telling the backend that `src` connected to `dst`
+pub fn header(version: ProxyProtocol, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+    match version {
+        ProxyProtocol::V1 => v1_header(src, dst),
+        ProxyProtocol::V2 => v2_header(src, dst),
+    }
+}
+
+/// Both addresses of a header must be of the same family, map v4 into v6 if they differ
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    fn to_v6(addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+            IpAddr::V6(_) => addr,
+        }
+    }
+
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+fn v1_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+fn v2_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::from(V2_SIGNATURE.as_slice());
+    // version 2, PROXY command
+    buf.push(0x21);
+
+    let mut addrs = vec![];
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            // TCP over IPv4
+            buf.push(0x11);
+            addrs.extend_from_slice(&s.octets());
+            addrs.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            // TCP over IPv6, `same_family` makes sure both are v6 here
+            buf.push(0x21);
+            addrs.extend_from_slice(&to_v6_octets(s));
+            addrs.extend_from_slice(&to_v6_octets(d));
+        }
+    }
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+
+    buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&addrs);
+    buf
+}
+
+fn to_v6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_header() {
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let dst = "10.0.0.1:443".parse().unwrap();
+        assert_eq!(
+            header(ProxyProtocol::V1, src, dst),
+            b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n"
+        );
+
+        let dst = "[::1]:443".parse().unwrap();
+        assert_eq!(
+            header(ProxyProtocol::V1, src, dst),
+            b"PROXY TCP6 ::ffff:192.168.0.1 ::1 56324 443\r\n"
+        );
+    }
+
+    #[test]
+    fn test_v2_header() {
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let dst = "10.0.0.1:443".parse().unwrap();
+        let buf = header(ProxyProtocol::V2, src, dst);
+
+        assert_eq!(&buf[..12], &V2_SIGNATURE);
+        assert_eq!(&buf[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&buf[16..20], &[192, 168, 0, 1]);
+        assert_eq!(&buf[20..24], &[10, 0, 0, 1]);
+        assert_eq!(&buf[24..], &[0xDC, 0x04, 0x01, 0xBB]);
+
+        let dst = "[::1]:443".parse().unwrap();
+        let buf = header(ProxyProtocol::V2, src, dst);
+        assert_eq!(&buf[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(buf.len(), 16 + 36);
+    }
+}
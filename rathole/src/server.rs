@@ -1,30 +1,69 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
 use rand::{Rng, RngCore};
-use tokio::io::{self, copy_bidirectional, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc, RwLock};
-use tokio::time;
-use tracing::{debug, error, info, info_span, instrument, Instrument, Span};
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
+use crate::admin::{self, ServiceStatus, StatusSource};
 use crate::config::{ServerConfig, ServiceConfig, ServiceType, TransportType};
+use crate::config_watcher::{ConfigChange, ServerServiceChange};
+use crate::hooks::{Event, Notifier};
+use crate::mux::{self, DataChannel, Side};
 use crate::protocol::{
-    read_auth, read_hello, Ack, ControlChannelCmd, DataChannelCmd, Hello, HASH_WIDTH_IN_BYTES,
+    read_auth, read_frame, read_hello, write_message, Ack, Capabilities, ControlChannelCmd,
+    DataChannelCmd, Handshake, Hello, UdpTraffic, HASH_WIDTH_IN_BYTES, UDP_BUFFER_SIZE,
 };
+use crate::traffic::{self, ServiceTraffic};
+#[cfg(feature = "tls")]
+use crate::transport::TlsTransport;
+#[cfg(feature = "websocket")]
+use crate::transport::WebsocketTransport;
 use crate::transport::{TcpTransport, Transport};
+use crate::visitor_filter::VisitorFilter;
 use crate::{protocol, Config};
 
 type ServiceDigest = protocol::Digest;
 
 const CHAN_SIZE: usize = 2048; // The capacity of various chans
 
-pub async fn run_server(config: &Config, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+const TRAFFIC_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long an accepted connection may take to finish the transport handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `heartbeat_interval` and `heartbeat_timeout` of `[server]`, zero disables either
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl From<&ServerConfig> for Heartbeat {
+    fn from(config: &ServerConfig) -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(config.heartbeat_interval),
+            timeout: Duration::from_secs(config.heartbeat_timeout),
+        }
+    }
+}
+
+pub async fn run_server(
+    config: &Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    update_rx: mpsc::Receiver<ConfigChange>,
+) -> Result<()> {
     let config = match &config.server {
         Some(config) => config,
         None => {
@@ -32,10 +71,30 @@ pub async fn run_server(config: &Config, shutdown_rx: broadcast::Receiver<bool>)
         }
     };
 
-    match config.transport {
+    match config.transport.transport_type {
         TransportType::Tcp => {
             let mut server = Server::<TcpTransport>::from(config).await?;
-            server.run(shutdown_rx).await?;
+            server.run(shutdown_rx, update_rx).await?;
+        }
+        TransportType::Tls => {
+            #[cfg(not(feature = "tls"))]
+            crate::helper::feature_not_compile("tls");
+
+            #[cfg(feature = "tls")]
+            {
+                let mut server = Server::<TlsTransport>::from(config).await?;
+                server.run(shutdown_rx, update_rx).await?;
+            }
+        }
+        TransportType::Websocket => {
+            #[cfg(not(feature = "websocket"))]
+            crate::helper::feature_not_compile("websocket");
+
+            #[cfg(feature = "websocket")]
+            {
+                let mut server = Server::<WebsocketTransport>::from(config).await?;
+                server.run(shutdown_rx, update_rx).await?;
+            }
         }
     }
 
@@ -45,7 +104,12 @@ pub async fn run_server(config: &Config, shutdown_rx: broadcast::Receiver<bool>)
 pub struct ControlChannelHandle<T: Transport> {
     // shutdown the control channel by dropping it
     _shutdown_tx: broadcast::Sender<bool>,
-    data_channel_tx: mpsc::Sender<T::Stream>,
+    data_channel_tx: mpsc::Sender<DataChannel<T::Stream>>,
+    // connections for `Hello::MuxChannel`
+    mux_channel_tx: mpsc::Sender<T::Stream>,
+    // data channels must present it to join this control channel
+    session_key: protocol::Digest,
+    established: Instant,
 }
 
 impl<T> ControlChannelHandle<T>
@@ -54,43 +118,74 @@ where
 {
     /// Create a control channel handle, where the control channel handling task
     /// and the connection pool task are created.
-
     fn run(
         conn: T::Stream,
-        service: ServiceConfig,
+        service: Service,
         service_digest: ServiceDigest,
+        session_key: protocol::Digest,
         control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
+        heartbeat: Heartbeat,
+        notifier: Arc<Notifier>,
     ) -> Self {
         info!("control channel established");
+        let Service {
+            config: service,
+            traffic,
+        } = service;
 
         let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
         let (data_ch_tx, data_ch_rx) = mpsc::channel(CHAN_SIZE * 2);
         let (data_ch_req_tx, data_ch_req_rx) = mpsc::unbounded_channel();
+        let (mux_ch_tx, mux_ch_rx) = mpsc::channel(CHAN_SIZE);
 
         match service.service_type {
             ServiceType::Tcp => tokio::spawn(run_tcp_connection_pool::<T>(
-                service.name.clone(),
-                service.bind_addrs.clone(),
-                service.xz_notify.clone(),
+                service.clone(),
+                traffic,
+                notifier.clone(),
+                data_ch_rx,
+                data_ch_req_tx,
+                shutdown_tx.subscribe(),
+            )),
+            ServiceType::Udp => tokio::spawn(run_udp_connection_pool::<T>(
+                service.clone(),
+                traffic,
+                notifier.clone(),
                 data_ch_rx,
                 data_ch_req_tx,
                 shutdown_tx.subscribe(),
             )),
         };
 
+        let data_ch_tx_for_mux = data_ch_tx.clone();
         tokio::spawn(
             async move {
-                if let Err(err) =
-                    ControlChannelHandle::<T>::do_run(conn, service, shutdown_rx, data_ch_req_rx)
-                        .await
-                        .with_context(|| "Failed to write data cmds")
+                let name = service.name.clone();
+                if let Err(err) = ControlChannelHandle::<T>::do_run(
+                    conn,
+                    service,
+                    shutdown_rx,
+                    data_ch_req_rx,
+                    data_ch_tx_for_mux,
+                    mux_ch_rx,
+                    heartbeat,
+                )
+                .await
+                .with_context(|| "Failed to run the control channel")
                 {
                     error!("{:?}", err);
                 }
 
+                // a reconnected client may have replaced this channel already
                 let mut write_guard = control_channels.write().await;
-                write_guard.remove(&service_digest);
+                if write_guard
+                    .get(&service_digest)
+                    .is_some_and(|handle| handle.session_key == session_key)
+                {
+                    write_guard.remove(&service_digest);
+                }
                 info!("Control channel shutting down");
+                notifier.notify(Event::ControlChannelDown { service: name });
             }
             .instrument(Span::current()),
         );
@@ -98,71 +193,185 @@ where
         Self {
             _shutdown_tx: shutdown_tx,
             data_channel_tx: data_ch_tx,
+            mux_channel_tx: mux_ch_tx,
+            session_key,
+            established: Instant::now(),
         }
     }
 
     // Run a control channel
     #[instrument(skip_all, fields(service = % service.name))]
     async fn do_run(
-        mut conn: T::Stream,
+        conn: T::Stream,
         service: ServiceConfig,
         mut shutdown_rx: broadcast::Receiver<bool>,
         mut data_ch_req_rx: mpsc::UnboundedReceiver<bool>,
+        data_ch_tx: mpsc::Sender<DataChannel<T::Stream>>,
+        mut mux_ch_rx: mpsc::Receiver<T::Stream>,
+        heartbeat: Heartbeat,
     ) -> Result<()>
     where
         T: Transport,
     {
-        let cmd = bincode::serialize(&ControlChannelCmd::CreateDataChannel).unwrap();
-        loop {
+        let (mut rd, mut wr) = io::split(conn);
+
+        // `read_frame` loses the bytes it has read when cancelled, so it can't be
+        // raced against the heartbeat ticks directly
+        let (frame_tx, mut frame_rx) = mpsc::channel(1);
+        let reader = tokio::spawn(async move {
+            loop {
+                let frame = read_frame(&mut rd).await;
+                let failed = frame.is_err();
+                if frame_tx.send(frame).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut ticker = time::interval_at(
+            Instant::now() + heartbeat.interval,
+            heartbeat.interval.max(Duration::from_secs(1)),
+        );
+        let mut last_reply = Instant::now();
+
+        // the mux channels of the client, visitors are spread over them in turn
+        let mut sessions: Vec<mux::Session> = Vec::new();
+        let mut drivers = Vec::new();
+        let mut next_session = 0;
+
+        let res = loop {
             tokio::select! {
                 val = data_ch_req_rx.recv() => {
                     match val {
                         Some(_) => {
-                            if let Err(e) = conn.write_all(&cmd).await.with_context(||"Failed to write data cmds") {
-                                eprintln!("{:?}", e);
-                                break;
+                            sessions.retain(|s| !s.is_closed());
+                            next_session += 1;
+                            let stream = sessions
+                                .get(next_session % sessions.len().max(1))
+                                .and_then(|s| s.open().ok());
+                            // a stream is ready right away, no round trip to the client
+                            let res = match stream {
+                                Some(stream) => data_ch_tx
+                                    .send(DataChannel::Muxed(stream))
+                                    .await
+                                    .map_err(|_| anyhow!("The connection pool is gone")),
+                                None => write_message(&mut wr, &ControlChannelCmd::CreateDataChannel)
+                                    .await
+                                    .with_context(|| "Failed to write data cmds"),
+                            };
+                            if let Err(e) = res {
+                                break Err(e);
                             }
                         }
                         None => {
-                            break;
+                            break Ok(());
                         }
                     }
                 },
-                val = read_hello(&mut conn) => {
-                    if Hello::ControlChannelClose == val? {
-                        break;
+                val = frame_rx.recv() => {
+                    let frame = match val {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(e)) => break Err(e),
+                        None => break Ok(()),
+                    };
+                    last_reply = Instant::now();
+                    match protocol::decode(&frame) {
+                        Ok(Hello::ControlChannelClose) => break Ok(()),
+                        Ok(_) => {}
+                        // something of a newer client that doesn't need an answer
+                        Err(e) => warn!("Ignoring an unknown hello: {:#}", e),
                     }
-                }
+                },
+                _ = ticker.tick(), if !heartbeat.interval.is_zero() => {
+                    if !heartbeat.timeout.is_zero() && last_reply.elapsed() > heartbeat.timeout {
+                        break Err(anyhow!("No heartbeat reply for {:?}", last_reply.elapsed()));
+                    }
+                    if let Err(e) = write_message(&mut wr, &ControlChannelCmd::Heartbeat).await.with_context(||"Failed to write heartbeat") {
+                        break Err(e);
+                    }
+                },
+                Some(conn) = mux_ch_rx.recv() => {
+                    let (session, _, driver) = mux::session(conn, Side::Server);
+                    sessions.push(session);
+                    drivers.retain(|d: &JoinHandle<()>| !d.is_finished());
+                    drivers.push(tokio::spawn(
+                        async move {
+                            if let Err(e) = driver.await {
+                                debug!("Mux channel closed: {:?}", e);
+                            }
+                        }
+                        .instrument(Span::current()),
+                    ));
+                },
                 _ = shutdown_rx.recv() => {
-                    break;
+                    break Ok(());
                 }
             }
-        }
+        };
 
-        Ok(())
+        reader.abort();
+        for driver in drivers {
+            driver.abort();
+        }
+        res
     }
 }
 
-#[instrument(skip_all, fields(service = %name))]
+#[instrument(skip_all, fields(service = %service.name))]
 async fn run_tcp_connection_pool<T: 'static + Transport>(
-    name: String,
-    bind_addrs: Vec<String>,
-    xz_notify: Option<String>,
-    mut data_ch_rx: mpsc::Receiver<T::Stream>,
+    service: ServiceConfig,
+    traffic: Arc<ServiceTraffic>,
+    notifier: Arc<Notifier>,
+    mut data_ch_rx: mpsc::Receiver<DataChannel<T::Stream>>,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
     shutdown_rx: broadcast::Receiver<bool>,
 ) -> Result<()> {
-    let mut stream_rx =
-        tcp_listen_and_service_bind(name, bind_addrs, xz_notify, data_ch_req_tx, shutdown_rx);
-    while let Some(mut steam) = stream_rx.recv().await {
-        if let Some(mut ch) = data_ch_rx.recv().await {
+    let mut stream_rx = tcp_listen_and_service_bind(
+        service.name.clone(),
+        service.bind_addrs.clone(),
+        notifier,
+        VisitorFilter::new(&service),
+        data_ch_req_tx.clone(),
+        shutdown_rx,
+    );
+    while let Some(Visitor {
+        conn: mut steam,
+        addr: visitor,
+        bind,
+        permit,
+    }) = stream_rx.recv().await
+    {
+        // channels of the client's pool may have been idle for long, dead ones are
+        // skipped and replaced from here only
+        let ch = loop {
+            match data_ch_rx.recv().await {
+                Some(mut ch) => {
+                    if is_alive(&mut ch).await {
+                        break Some(ch);
+                    }
+                    debug!("Dropping a closed idle data channel");
+                    let _ = data_ch_req_tx.send(true);
+                }
+                None => break None,
+            }
+        };
+        if let Some(mut ch) = ch {
+            let traffic = traffic.clone();
             tokio::spawn(
                 async move {
-                    let cmd = bincode::serialize(&DataChannelCmd::StartForwardTcp).unwrap();
-                    if ch.write_all(&cmd).await.is_ok() {
+                    let cmd = DataChannelCmd::StartForwardTcp { visitor, bind };
+                    if write_message(&mut ch, &cmd).await.is_ok() {
                         info!("start forwarding");
-                        let _ = copy_bidirectional(&mut ch, &mut steam).await;
+                        if let Ok((inbound, outbound)) =
+                            traffic::copy_bidirectional(&mut steam, &mut ch, &traffic).await
+                        {
+                            info!(
+                                "Visitor {} closed, {} bytes in, {} bytes out",
+                                visitor, inbound, outbound
+                            );
+                        }
                     }
+                    drop(permit);
                 }
                 .instrument(Span::current()),
             );
@@ -174,15 +383,35 @@ async fn run_tcp_connection_pool<T: 'static + Transport>(
     Ok(())
 }
 
+/// Whether a data channel waiting for its command is still open. The client sends
+/// nothing before the command, anything readable means it's closed
+async fn is_alive<S: AsyncRead + Unpin>(ch: &mut S) -> bool {
+    let mut buf = [0u8; 1];
+    time::timeout(Duration::ZERO, ch.read(&mut buf))
+        .await
+        .is_err()
+}
+
+/// An accepted visitor of a tcp service
+struct Visitor {
+    conn: TcpStream,
+    addr: SocketAddr,
+    // the local address it connected to
+    bind: SocketAddr,
+    // held as long as the visitor stays connected, see `max_connections`
+    permit: Option<OwnedSemaphorePermit>,
+}
+
 ///监听对应tcp端口并绑定服务
 #[instrument(skip_all, fields(service = %name))]
 fn tcp_listen_and_service_bind(
     name: String,
     addrs: Vec<String>,
-    xz_notify: Option<String>,
+    notifier: Arc<Notifier>,
+    mut filter: VisitorFilter,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
     mut shutdown_rx: broadcast::Receiver<bool>,
-) -> mpsc::Receiver<TcpStream> {
+) -> mpsc::Receiver<Visitor> {
     let (tx, rx) = mpsc::channel(CHAN_SIZE);
 
     tokio::spawn(
@@ -210,17 +439,17 @@ fn tcp_listen_and_service_bind(
                 ..Default::default()
             };
 
-            if let Some(xz_notify) = xz_notify {
-                if let Ok(add) = listener.local_addr() {
-                    if ureq::get(&xz_notify)
-                        .query("title", &add.port().to_string())
-                        .call()
-                        .is_err()
-                    {
-                        eprintln!("failed to notify xz, notify_url:{:?}", xz_notify);
-                    }
+            let bind = match listener.local_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
                 }
-            }
+            };
+            notifier.notify(Event::ServiceBound {
+                service: name,
+                port: bind.port(),
+            });
 
             loop {
                 tokio::select! {
@@ -237,6 +466,20 @@ fn tcp_listen_and_service_bind(
                                 }
                             }
                             Ok((incoming, addr)) => {
+                                let permit = match filter.admit(addr.ip()) {
+                                    Ok(permit) => permit,
+                                    Err(reason) => {
+                                        // dropping the connection closes it right away
+                                        info!(
+                                            "Rejected visitor from {}: {}, {} rejected so far",
+                                            addr,
+                                            reason,
+                                            filter.rejected()
+                                        );
+                                        continue;
+                                    }
+                                };
+
                                 if let Err(e) = data_ch_req_tx.send(true)
                                 .with_context(|| "Failed to send data channel create request") {
                                     error!("{:?}", e);
@@ -247,7 +490,14 @@ fn tcp_listen_and_service_bind(
 
                                 info!("New visitor from {}", addr);
 
-                                let _ = tx.send(incoming).await;
+                                let bind = incoming.local_addr().unwrap_or(bind);
+                                let visitor = Visitor {
+                                    conn: incoming,
+                                    addr,
+                                    bind,
+                                    permit,
+                                };
+                                let _ = tx.send(visitor).await;
                             }
                         }
                     },
@@ -264,21 +514,151 @@ fn tcp_listen_and_service_bind(
     rx
 }
 
+/// Every datagram of a udp service goes through a single data channel, a new one is
+/// requested whenever the current one breaks
+#[instrument(skip_all, fields(service = %service.name))]
+async fn run_udp_connection_pool<T: 'static + Transport>(
+    service: ServiceConfig,
+    traffic: Arc<ServiceTraffic>,
+    notifier: Arc<Notifier>,
+    mut data_ch_rx: mpsc::Receiver<DataChannel<T::Stream>>,
+    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+) -> Result<()> {
+    let filter = VisitorFilter::new(&service);
+    let mut socket = None;
+    for addr in &service.bind_addrs {
+        match UdpSocket::bind(addr).await {
+            Ok(s) => {
+                socket = Some(s);
+                break;
+            }
+            Err(e) => error!("Failed to bind udp {}: {}", addr, e),
+        }
+    }
+    let socket = match socket {
+        Some(s) => Arc::new(s),
+        None => bail!("failed to bind to any adds:{:?}", service.bind_addrs),
+    };
+
+    if let Ok(addr) = socket.local_addr() {
+        notifier.notify(Event::ServiceBound {
+            service: service.name.clone(),
+            port: addr.port(),
+        });
+    }
+
+    let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+    loop {
+        data_ch_req_tx
+            .send(true)
+            .with_context(|| "Failed to send data channel create request")?;
+
+        let mut ch = tokio::select! {
+            ch = data_ch_rx.recv() => match ch {
+                Some(ch) => ch,
+                None => break,
+            },
+            _ = shutdown_rx.recv() => break,
+        };
+
+        if let Err(e) = write_message(&mut ch, &DataChannelCmd::StartForwardUdp).await {
+            error!("Failed to start udp forwarding: {}", e);
+            continue;
+        }
+        info!("start forwarding udp");
+
+        let (rd, mut wr) = io::split(ch);
+        let mut reader = tokio::spawn(forward_udp_to_visitors(rd, socket.clone(), traffic.clone()));
+
+        // visitors -> data channel
+        let shutdown = loop {
+            tokio::select! {
+                val = socket.recv_from(&mut buf) => {
+                    let (n, from) = match val {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!("{}", e);
+                            continue;
+                        }
+                    };
+                    if !filter.is_allowed(from.ip()) {
+                        debug!("Dropped a datagram from {}: not allowed", from);
+                        continue;
+                    }
+                    traffic.inbound(n).await;
+                    if let Err(e) = UdpTraffic::write_slice(&mut wr, from, &buf[..n]).await {
+                        error!("{:?}", e);
+                        break false;
+                    }
+                },
+                val = &mut reader => {
+                    if let Ok(Err(e)) = val {
+                        error!("{:?}", e);
+                    }
+                    break false;
+                },
+                _ = shutdown_rx.recv() => break true,
+            }
+        };
+
+        reader.abort();
+        if shutdown {
+            break;
+        }
+        info!("udp data channel broken, requesting a new one");
+    }
+
+    info!("udp pool close");
+    Ok(())
+}
+
+/// data channel -> visitors
+async fn forward_udp_to_visitors<R: AsyncRead + Unpin>(
+    mut rd: R,
+    socket: Arc<UdpSocket>,
+    traffic: Arc<ServiceTraffic>,
+) -> Result<()> {
+    loop {
+        let UdpTraffic { from, data } = UdpTraffic::read(&mut rd).await?;
+        traffic.outbound(data.len()).await;
+        socket.send_to(&data, from).await?;
+    }
+}
+
+/// A `[server.services]` entry, its traffic outlives the control channels
+#[derive(Clone)]
+struct Service {
+    config: ServiceConfig,
+    traffic: Arc<ServiceTraffic>,
+}
+
+impl From<ServiceConfig> for Service {
+    fn from(config: ServiceConfig) -> Self {
+        Service {
+            traffic: Arc::new(ServiceTraffic::new(&config)),
+            config,
+        }
+    }
+}
+
 struct Server<'a, T: Transport> {
     // `[server]` config
     config: &'a ServerConfig,
     // `[server.services]` config, indexed by ServiceDigest
-    services: Arc<RwLock<HashMap<ServiceDigest, ServiceConfig>>>,
+    services: Arc<RwLock<HashMap<ServiceDigest, Service>>>,
     // Collection of control channels
     control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
     // Wrapper around the transport layer
     transport: Arc<T>,
+    // `[[server.hooks]]`
+    notifier: Arc<Notifier>,
 }
 
-fn generate_service_hashmap(server_config: &ServerConfig) -> HashMap<ServiceDigest, ServiceConfig> {
+fn generate_service_hashmap(server_config: &ServerConfig) -> HashMap<ServiceDigest, Service> {
     let mut ret = HashMap::new();
     for (key, config) in &server_config.services {
-        ret.insert(protocol::digest(key.as_bytes()), config.clone());
+        ret.insert(protocol::digest(key.as_bytes()), config.clone().into());
     }
     ret
 }
@@ -290,10 +670,15 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
             services: Arc::new(RwLock::new(generate_service_hashmap(config))),
             control_channels: Arc::new(RwLock::new(HashMap::new())),
             transport: Arc::new(T::new(&config.transport).await?),
+            notifier: Arc::new(Notifier::new(config.hooks.clone())),
         })
     }
 
-    pub async fn run(&mut self, mut shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
+    pub async fn run(
+        &mut self,
+        mut shutdown_rx: broadcast::Receiver<bool>,
+        mut update_rx: mpsc::Receiver<ConfigChange>,
+    ) -> Result<()> {
         let l = self
             .transport
             .bind(&self.config.bind_addr)
@@ -302,6 +687,15 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
 
         info!("Listening at {}", self.config.bind_addr);
 
+        let summary = tokio::spawn(log_traffic_summary(self.services.clone()));
+        let admin = self.config.admin.as_ref().map(|config| {
+            let status = Arc::new(ServerStatus {
+                services: self.services.clone(),
+                control_channels: self.control_channels.clone(),
+            });
+            tokio::spawn(admin::run_admin(config.bind_addr.clone(), status))
+        });
+
         let mut backoff = ExponentialBackoff {
             max_interval: Duration::from_millis(100),
             max_elapsed_time: None,
@@ -327,9 +721,23 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
 
                             let services = self.services.clone();
                             let control_channels = self.control_channels.clone();
+                            let heartbeat = Heartbeat::from(self.config);
+                            let notifier = self.notifier.clone();
+                            let transport = self.transport.clone();
                             tokio::spawn(async move {
                                 info!("Handling");
-                                if let Err(err) = handle_connection(conn, addr, services, control_channels)
+                                let conn = match time::timeout(HANDSHAKE_TIMEOUT, transport.handshake(conn)).await {
+                                    Ok(Ok(conn)) => conn,
+                                    Ok(Err(err)) => {
+                                        error!("{:?}", err);
+                                        return;
+                                    }
+                                    Err(_) => {
+                                        error!("The transport handshake timed out");
+                                        return;
+                                    }
+                                };
+                                if let Err(err) = handle_connection(conn, addr, services, control_channels, heartbeat, notifier)
                                 .await
                                 .with_context(|| "Failed to handle connection".to_string()) {
                                     error!("{:?}", err);
@@ -341,81 +749,210 @@ impl<'a, T: 'static + Transport> Server<'a, T> {
                 _ = shutdown_rx.recv() => {
                     info!("Shutting down gracefully...");
                     break;
+                },
+                Some(event) = update_rx.recv() => {
+                    if let ConfigChange::ServerChange(change) = event {
+                        self.handle_hot_reload(change).await;
+                    }
                 }
             }
         }
 
+        summary.abort();
+        if let Some(admin) = admin {
+            admin.abort();
+        }
+        // dropping the handles shuts down the control channels and their listeners
+        self.control_channels.write().await.clear();
+
         Ok(())
     }
+
+    async fn handle_hot_reload(&mut self, change: ServerServiceChange) {
+        match change {
+            ServerServiceChange::Add(service) => {
+                let digest = protocol::digest(service.name.as_bytes());
+                info!("Adding service {}", service.name);
+                self.services.write().await.insert(digest, service.into());
+            }
+            ServerServiceChange::Delete(name) => {
+                let digest = protocol::digest(name.as_bytes());
+                info!("Deleting service {}", name);
+                self.services.write().await.remove(&digest);
+                // dropping the handle closes the listener along with the control channel
+                self.control_channels.write().await.remove(&digest);
+            }
+        }
+    }
+}
+
+struct ServerStatus<T: Transport> {
+    services: Arc<RwLock<HashMap<ServiceDigest, Service>>>,
+    control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
+}
+
+#[async_trait]
+impl<T: 'static + Transport> StatusSource for ServerStatus<T> {
+    async fn status(&self) -> Vec<ServiceStatus> {
+        let services = self.services.read().await;
+        let control_channels = self.control_channels.read().await;
+        services
+            .iter()
+            .map(|(digest, service)| {
+                let (bytes_in, bytes_out) = service.traffic.snapshot();
+                let handle = control_channels.get(digest);
+                ServiceStatus {
+                    name: service.config.name.clone(),
+                    control_channel: handle.is_some(),
+                    uptime: handle.map(|h| h.established.elapsed().as_secs()),
+                    active_data_channels: service.traffic.active.load(Ordering::Relaxed),
+                    bytes_in,
+                    bytes_out,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Log what every service has forwarded since the last summary, idle ones are skipped
+async fn log_traffic_summary(services: Arc<RwLock<HashMap<ServiceDigest, Service>>>) {
+    // the counters of the last summary, a reloaded service starts over
+    let mut last: HashMap<ServiceDigest, (Arc<ServiceTraffic>, (u64, u64))> = HashMap::new();
+    let mut ticker = time::interval_at(
+        Instant::now() + TRAFFIC_SUMMARY_INTERVAL,
+        TRAFFIC_SUMMARY_INTERVAL,
+    );
+
+    loop {
+        ticker.tick().await;
+        let services = services.read().await;
+        last.retain(|digest, _| services.contains_key(digest));
+
+        for (digest, service) in services.iter() {
+            let (inbound, outbound) = service.traffic.snapshot();
+            let (last_in, last_out) = match last.get(digest) {
+                Some((traffic, counters)) if Arc::ptr_eq(traffic, &service.traffic) => *counters,
+                _ => (0, 0),
+            };
+            last.insert(*digest, (service.traffic.clone(), (inbound, outbound)));
+
+            if (inbound, outbound) != (last_in, last_out) {
+                info!(
+                    "Service {}: {} bytes in, {} bytes out in the last {:?}, {} bytes in, {} bytes out in total",
+                    service.config.name,
+                    inbound - last_in,
+                    outbound - last_out,
+                    TRAFFIC_SUMMARY_INTERVAL,
+                    inbound,
+                    outbound
+                );
+            }
+        }
+    }
 }
 
 async fn handle_connection<T: 'static + Transport>(
     mut conn: T::Stream,
     addr: SocketAddr,
-    services: Arc<RwLock<HashMap<ServiceDigest, ServiceConfig>>>,
+    services: Arc<RwLock<HashMap<ServiceDigest, Service>>>,
     control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
+    heartbeat: Heartbeat,
+    notifier: Arc<Notifier>,
 ) -> Result<()> {
     let hello = read_hello(&mut conn).await?;
     match hello {
-        Hello::ControlChannel(service_digest) => {
-            do_control_channel_handshake(conn, addr, services, control_channels, service_digest)
-                .await?;
+        Hello::ControlChannel(client, service_digest) => {
+            do_control_channel_handshake(
+                conn,
+                addr,
+                services,
+                control_channels,
+                service_digest,
+                client,
+                heartbeat,
+                notifier,
+            )
+            .await?;
+        }
+        Hello::DataChannel(session_key) => {
+            do_data_channel_handshake(conn, control_channels, session_key, false).await?;
         }
-        Hello::DataChannel(nonce) => {
-            do_data_channel_handshake(conn, control_channels, nonce).await?;
+        Hello::MuxChannel(session_key) => {
+            do_data_channel_handshake(conn, control_channels, session_key, true).await?;
         }
         _ => {}
     }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn do_control_channel_handshake<T: 'static + Transport>(
     mut conn: T::Stream,
     addr: SocketAddr,
-    services: Arc<RwLock<HashMap<ServiceDigest, ServiceConfig>>>,
+    services: Arc<RwLock<HashMap<ServiceDigest, Service>>>,
     control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
     service_digest: ServiceDigest,
+    client: Handshake,
+    heartbeat: Heartbeat,
+    notifier: Arc<Notifier>,
 ) -> Result<()> {
     info!("New control channel incoming from {}", addr);
 
-    let mut nonce = vec![0u8; HASH_WIDTH_IN_BYTES];
+    let mut nonce = [0u8; HASH_WIDTH_IN_BYTES];
     rand::thread_rng().fill_bytes(&mut nonce);
 
-    let nonce_send = Hello::ControlChannel(nonce.clone().try_into().unwrap());
-    conn.write_all(&bincode::serialize(&nonce_send).unwrap())
-        .await?;
+    // sent even if the versions don't match, so the client can tell why it's rejected
+    let nonce_send = Hello::ControlChannel(Handshake::local(), nonce);
+    write_message(&mut conn, &nonce_send).await?;
+
+    let negotiated = Handshake::local()
+        .negotiate(&client)
+        .with_context(|| format!("Rejecting the control channel from {}", addr))?;
+    debug!("Speaking protocol version {}", negotiated.version);
+    // an old client wouldn't answer, don't kill its control channel for that
+    let heartbeat = if negotiated.capabilities.contains(Capabilities::HEARTBEAT) {
+        heartbeat
+    } else {
+        Heartbeat {
+            interval: Duration::ZERO,
+            timeout: Duration::ZERO,
+        }
+    };
 
     let services_guard = services.read().await;
-    let services_config = match services_guard.get(&service_digest) {
-        Some(v) => v,
+    let service = match services_guard.get(&service_digest) {
+        Some(v) => v.clone(),
         None => {
-            conn.write_all(&bincode::serialize(&Ack::ServiceNotExist).unwrap())
-                .await?;
-            bail!("No such a service {}", hex::encode(&service_digest));
+            write_message(&mut conn, &Ack::ServiceNotExist).await?;
+            bail!("No such a service {}", hex::encode(service_digest));
         }
     };
 
     let protocol::Auth(d) = read_auth(&mut conn).await?;
 
-    let service_name = &services_config.name;
+    drop(services_guard);
+    let service_name = &service.config.name;
 
-    let mut concat = Vec::from(services_config.token.as_ref().unwrap().as_bytes());
-    concat.append(&mut nonce);
+    let token = service.config.token.as_ref().unwrap();
+    let mut concat = Vec::from(token.as_bytes());
+    concat.extend_from_slice(&nonce);
 
     let session_key = protocol::digest(&concat);
-    if session_key != d {
-        conn.write_all(&bincode::serialize(&Ack::AuthFailed).unwrap())
-            .await?;
+    if !protocol::digest_eq(&session_key, &d) {
+        write_message(&mut conn, &Ack::AuthFailed).await?;
         debug!(
             "Expect {}, but got {}",
             hex::encode(session_key),
             hex::encode(d)
         );
 
+        notifier.notify(Event::AuthFailed {
+            service: service_name.clone(),
+            addr,
+        });
         bail!("Service {} failed the authentication", service_name);
     } else {
-        let service_config = services_config.clone();
-        drop(services_guard);
+        let data_channel_key = protocol::data_channel_key(token, &nonce);
 
         let mut h = control_channels.write().await;
 
@@ -426,37 +963,80 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
             );
         }
 
-        conn.write_all(&bincode::serialize(&Ack::Ok).unwrap())
-            .await?;
+        write_message(&mut conn, &Ack::Ok).await?;
 
+        let up = Event::ControlChannelUp {
+            service: service_name.clone(),
+            addr,
+        };
         let handle = ControlChannelHandle::run(
             conn,
-            service_config,
+            service,
             service_digest,
+            data_channel_key,
             control_channels.clone(),
+            heartbeat,
+            notifier.clone(),
         );
 
         let _ = h.insert(service_digest, handle);
+        notifier.notify(up);
     }
 
     Ok(())
 }
 
 async fn do_data_channel_handshake<T: 'static + Transport>(
-    conn: T::Stream,
+    mut conn: T::Stream,
     control_channels: Arc<RwLock<HashMap<ServiceDigest, ControlChannelHandle<T>>>>,
-    nonce: ServiceDigest,
+    session_key: protocol::Digest,
+    mux: bool,
 ) -> Result<()> {
-    let control_channels_guard = control_channels.read().await;
-    match control_channels_guard.get(&nonce) {
-        Some(handle) => {
-            handle.data_channel_tx.send(conn).await?;
-            info!("data channel ready")
+    // nothing is written or sent under the lock, a stalled peer would hold up
+    // every control channel coming and going
+    let senders = control_channels
+        .read()
+        .await
+        .values()
+        .find(|handle| protocol::digest_eq(&handle.session_key, &session_key))
+        .map(|handle| {
+            (
+                handle.data_channel_tx.clone(),
+                handle.mux_channel_tx.clone(),
+            )
+        });
+    match senders {
+        Some((data_channel_tx, mux_channel_tx)) => {
+            write_message(&mut conn, &Ack::Ok).await?;
+            if mux {
+                mux_channel_tx.send(conn).await?;
+                info!("mux channel ready")
+            } else {
+                data_channel_tx.send(DataChannel::Direct(conn)).await?;
+                info!("data channel ready")
+            }
         }
         None => {
-            eprintln!("Data channels has incorrect nonce");
+            write_message(&mut conn, &Ack::AuthFailed).await?;
+            bail!(
+                "Data channel has incorrect session key {}",
+                hex::encode(session_key)
+            );
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_is_alive() {
+        let (mut ch, peer) = io::duplex(64);
+        assert!(is_alive(&mut ch).await);
+        drop(peer);
+        assert!(!is_alive(&mut ch).await);
+    }
+}
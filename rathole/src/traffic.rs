@@ -0,0 +1,209 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+use crate::config::ServiceConfig;
+
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Bytes a service has forwarded in both directions and its rate limits,
+/// shared by all of its visitors
+#[derive(Debug, Default)]
+pub struct ServiceTraffic {
+    /// visitors -> service
+    pub inbound: AtomicU64,
+    /// service -> visitors
+    pub outbound: AtomicU64,
+    /// connections in `copy_bidirectional` right now
+    pub active: AtomicUsize,
+    inbound_limit: Option<TokenBucket>,
+    outbound_limit: Option<TokenBucket>,
+}
+
+impl ServiceTraffic {
+    pub fn new(config: &ServiceConfig) -> Self {
+        ServiceTraffic {
+            inbound: AtomicU64::new(0),
+            outbound: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            inbound_limit: config.inbound_limit.map(TokenBucket::new),
+            outbound_limit: config.outbound_limit.map(TokenBucket::new),
+        }
+    }
+
+    /// Account for `n` bytes from a visitor, waiting if the service goes too fast
+    pub async fn inbound(&self, n: usize) {
+        self.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(bucket) = &self.inbound_limit {
+            bucket.consume(n).await;
+        }
+    }
+
+    /// Account for `n` bytes to a visitor, waiting if the service goes too fast
+    pub async fn outbound(&self, n: usize) {
+        self.outbound.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(bucket) = &self.outbound_limit {
+            bucket.consume(n).await;
+        }
+    }
+
+    /// `(inbound, outbound)` so far
+    pub fn snapshot(&self) -> (u64, u64) {
+        (
+            self.inbound.load(Ordering::Relaxed),
+            self.outbound.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Allows `rate` bytes per second with bursts of up to one second worth of bytes.
+/// Consuming more than available borrows from the future, the caller waits it off
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    // (tokens, last refill)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    async fn consume(&self, n: usize) {
+        let wait = self.reserve(n, Instant::now());
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Take `n` tokens, returns how long to wait until they would have been available
+    fn reserve(&self, n: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate);
+        *last = now;
+
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// Like `copy_bidirectional`, but accounts every byte to `traffic` and obeys its limits.
+/// Returns `(inbound, outbound)` bytes of this connection
+pub async fn copy_bidirectional<V, S>(
+    visitor: &mut V,
+    service: &mut S,
+    traffic: &ServiceTraffic,
+) -> io::Result<(u64, u64)>
+where
+    V: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut visitor_rd, mut visitor_wr) = io::split(visitor);
+    let (mut service_rd, mut service_wr) = io::split(service);
+
+    let _active = Active::new(&traffic.active);
+    tokio::try_join!(
+        copy_one_way(&mut visitor_rd, &mut service_wr, |n| traffic.inbound(n)),
+        copy_one_way(&mut service_rd, &mut visitor_wr, |n| traffic.outbound(n)),
+    )
+}
+
+/// Counts a connection as active until dropped, even if the copy is cancelled
+struct Active<'a>(&'a AtomicUsize);
+
+impl<'a> Active<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Active(counter)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn copy_one_way<R, W, F, Fut>(rd: &mut R, wr: &mut W, account: F) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(usize) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut total = 0;
+    loop {
+        let n = rd.read(&mut buf).await?;
+        if n == 0 {
+            wr.shutdown().await?;
+            return Ok(total);
+        }
+        account(n).await;
+        wr.write_all(&buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+
+        // a full second worth of burst
+        assert_eq!(bucket.reserve(1000, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, now), Duration::from_millis(500));
+
+        // the debt is paid off after half a second, refilled after another
+        let now = now + Duration::from_millis(1500);
+        assert_eq!(bucket.reserve(1000, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(1, now), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn test_copy_counts_both_directions() -> io::Result<()> {
+        let traffic = ServiceTraffic::new(&ServiceConfig::default());
+        let (mut visitor, mut visitor_peer) = io::duplex(64);
+        let (mut service, mut service_peer) = io::duplex(64);
+
+        let peers = async move {
+            visitor_peer.write_all(b"hello").await?;
+            visitor_peer.shutdown().await?;
+            let mut buf = [0u8; 5];
+            service_peer.read_exact(&mut buf).await?;
+            service_peer.write_all(b"hi").await?;
+            service_peer.shutdown().await?;
+            let mut buf = vec![];
+            visitor_peer.read_to_end(&mut buf).await?;
+            assert_eq!(buf, b"hi");
+            Ok::<_, io::Error>(())
+        };
+
+        let (copied, _) = tokio::try_join!(
+            copy_bidirectional(&mut visitor, &mut service, &traffic),
+            peers
+        )?;
+        assert_eq!(copied, (5, 2));
+        assert_eq!(traffic.snapshot(), (5, 2));
+        assert_eq!(traffic.active.load(Ordering::Relaxed), 0);
+        Ok(())
+    }
+}
@@ -1,5 +1,14 @@
+mod proxy;
 mod tcp;
 pub use tcp::TcpTransport;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::TlsTransport;
+#[cfg(feature = "websocket")]
+mod websocket;
+#[cfg(feature = "websocket")]
+pub use websocket::WebsocketTransport;
 
 use std::fmt::Debug;
 use std::net::SocketAddr;
@@ -9,17 +18,22 @@ use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::ToSocketAddrs;
 
-use crate::config::TransportType;
+use crate::config::TransportConfig;
 
 #[async_trait]
 pub trait Transport: Debug + Send + Sync {
     type Acceptor: Send + Sync;
+    /// What `accept` hands over before `handshake` makes it a `Stream`
+    type RawStream: Send + Sync;
     type Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + Debug;
 
-    async fn new(transport_type: &TransportType) -> Result<Self>
+    async fn new(config: &TransportConfig) -> Result<Self>
     where
         Self: Sized;
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor>;
-    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::Stream, SocketAddr)>;
+    /// Only waits for the connection, the accept loop must not be held up by a slow peer
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)>;
+    /// The rest of accepting, e.g. the tls handshake. Run it in the task of the connection
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream>;
     async fn connect(&self, addr: &str) -> Result<Self::Stream>;
 }
@@ -4,35 +4,53 @@ use anyhow::Result;
 use async_trait::async_trait;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
-use crate::config::TransportType;
+use url::Url;
+
+use crate::config::{Masked, TransportConfig};
 use crate::helper::set_tcp_keep_alive;
-use crate::transport::Transport;
+use crate::transport::{proxy, Transport};
 
 #[derive(Debug)]
-pub struct TcpTransport {}
+pub struct TcpTransport {
+    // dial through it if set
+    proxy: Option<Masked<Url>>,
+}
 
 #[async_trait]
 impl Transport for TcpTransport {
     type Acceptor = TcpListener;
+    type RawStream = TcpStream;
     type Stream = TcpStream;
 
-    async fn new(_: &TransportType) -> Result<Self> {
-        Ok(TcpTransport {})
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        Ok(TcpTransport {
+            proxy: config.proxy.clone(),
+        })
     }
 
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
         Ok(TcpListener::bind(addr).await?)
     }
 
-    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::Stream, SocketAddr)> {
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
         let (s, addr) = a.accept().await?;
         set_tcp_keep_alive(&s);
+        // commands and mux frames are small, don't let them wait for delayed acks
+        let _ = s.set_nodelay(true);
         Ok((s, addr))
     }
 
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        Ok(conn)
+    }
+
     async fn connect(&self, addr: &str) -> Result<Self::Stream> {
-        let s = TcpStream::connect(addr).await?;
+        let s = match &self.proxy {
+            Some(proxy) => proxy::connect(proxy, addr).await?,
+            None => TcpStream::connect(addr).await?,
+        };
         set_tcp_keep_alive(&s);
+        let _ = s.set_nodelay(true);
         Ok(s)
     }
 }
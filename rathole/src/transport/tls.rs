@@ -0,0 +1,154 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::config::{TlsConfig, TransportConfig};
+use crate::transport::{TcpTransport, Transport};
+
+pub struct TlsTransport {
+    tcp: TcpTransport,
+    config: TlsConfig,
+    connector: Option<TlsConnector>,
+    acceptor: Option<TlsAcceptor>,
+}
+
+impl Debug for TlsTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Transport for TlsTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = TlsStream<TcpStream>;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        let tcp = TcpTransport::new(config).await?;
+        let config = config
+            .tls
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing tls config"))?;
+
+        let connector = match config.trusted_root.as_ref() {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots
+                        .add(&cert)
+                        .with_context(|| format!("Failed to trust {}", path))?;
+                }
+                let client_config = rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                Some(TlsConnector::from(Arc::new(client_config)))
+            }
+            None => None,
+        };
+
+        let acceptor = match (config.cert.as_ref(), config.key.as_ref()) {
+            (Some(cert), Some(key)) => {
+                let server_config = rustls::ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_single_cert(load_certs(cert)?, load_key(key)?)
+                    .with_context(|| "Invalid certificate or key")?;
+                Some(TlsAcceptor::from(Arc::new(server_config)))
+            }
+            _ => None,
+        };
+
+        Ok(TlsTransport {
+            tcp,
+            config: config.clone(),
+            connector,
+            acceptor,
+        })
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        self.tcp.bind(addr).await
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        self.tcp.accept(a).await
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let acceptor = self
+            .acceptor
+            .as_ref()
+            .ok_or_else(|| anyhow!("The tls transport has no certificate to accept with"))?;
+
+        let conn = acceptor
+            .accept(conn)
+            .await
+            .with_context(|| "Tls handshake failed")?;
+        Ok(TlsStream::Server(conn))
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let connector = self
+            .connector
+            .as_ref()
+            .ok_or_else(|| anyhow!("The tls transport has no trusted root to connect with"))?;
+
+        let hostname = match self.config.hostname.as_ref() {
+            Some(hostname) => hostname.as_str(),
+            None => host_of(addr),
+        };
+        let server_name = ServerName::try_from(hostname)
+            .with_context(|| format!("Invalid tls hostname {}", hostname))?;
+
+        let conn = self.tcp.connect(addr).await?;
+        let conn = connector
+            .connect(server_name, conn)
+            .await
+            .with_context(|| format!("Tls handshake with {} failed", addr))?;
+        Ok(TlsStream::Client(conn))
+    }
+}
+
+/// `example.com:2333` -> `example.com`, `[::1]:2333` -> `::1`
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificates from {}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Failed to read private key from {}", path))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {}", path)),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Debug;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_hdr_async, client_async, WebSocketStream};
+
+use crate::config::TransportConfig;
+use crate::transport::{TcpTransport, Transport};
+
+#[cfg(feature = "tls")]
+use crate::transport::TlsTransport;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsStream;
+
+/// What the WebSocket is spoken over
+#[derive(Debug)]
+enum Inner {
+    Tcp(TcpTransport),
+    #[cfg(feature = "tls")]
+    Tls(TlsTransport),
+}
+
+#[derive(Debug)]
+pub struct WebsocketTransport {
+    inner: Inner,
+    path: String,
+}
+
+#[async_trait]
+impl Transport for WebsocketTransport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = WebsocketStream<MaybeTlsStream>;
+
+    async fn new(config: &TransportConfig) -> Result<Self> {
+        let websocket = config.websocket.clone().unwrap_or_default();
+        let inner = if websocket.tls {
+            #[cfg(not(feature = "tls"))]
+            crate::helper::feature_not_compile("tls");
+
+            #[cfg(feature = "tls")]
+            Inner::Tls(TlsTransport::new(config).await?)
+        } else {
+            Inner::Tcp(TcpTransport::new(config).await?)
+        };
+        Ok(WebsocketTransport {
+            inner,
+            path: websocket.path,
+        })
+    }
+
+    async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
+        match &self.inner {
+            Inner::Tcp(t) => t.bind(addr).await,
+            #[cfg(feature = "tls")]
+            Inner::Tls(t) => t.bind(addr).await,
+        }
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> Result<(Self::RawStream, SocketAddr)> {
+        match &self.inner {
+            Inner::Tcp(t) => t.accept(a).await,
+            #[cfg(feature = "tls")]
+            Inner::Tls(t) => t.accept(a).await,
+        }
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
+        let conn = match &self.inner {
+            Inner::Tcp(t) => MaybeTlsStream::Tcp(t.handshake(conn).await?),
+            #[cfg(feature = "tls")]
+            Inner::Tls(t) => MaybeTlsStream::Tls(Box::new(t.handshake(conn).await?)),
+        };
+        // the error response is what tungstenite's callback returns
+        #[allow(clippy::result_large_err)]
+        let check_path = |req: &Request, resp: Response| {
+            if req.uri().path() == self.path {
+                Ok(resp)
+            } else {
+                let mut resp = ErrorResponse::new(None);
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                Err(resp)
+            }
+        };
+        let conn = accept_hdr_async(conn, check_path)
+            .await
+            .with_context(|| "WebSocket handshake failed")?;
+        Ok(WebsocketStream::new(conn))
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Self::Stream> {
+        let (conn, scheme) = match &self.inner {
+            Inner::Tcp(t) => (MaybeTlsStream::Tcp(t.connect(addr).await?), "ws"),
+            #[cfg(feature = "tls")]
+            Inner::Tls(t) => (MaybeTlsStream::Tls(Box::new(t.connect(addr).await?)), "wss"),
+        };
+        let url = format!("{}://{}{}", scheme, addr, self.path);
+        let (conn, _) = client_async(url.as_str(), conn)
+            .await
+            .with_context(|| format!("WebSocket handshake with {} failed", url))?;
+        Ok(WebsocketStream::new(conn))
+    }
+}
+
+/// The connection under the WebSocket, encrypted with `websocket.tls`
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Bytes over binary messages. An empty message ends the sender's direction, so
+/// half-closing works as on tcp, which a WebSocket close frame can't do
+pub struct WebsocketStream<S> {
+    inner: WebSocketStream<S>,
+    // the unread rest of the last message
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_eof: bool,
+    // bytes of the message handed to `inner` but not flushed yet
+    flushing: Option<usize>,
+    // the empty message was handed to `inner`
+    write_eof: bool,
+}
+
+impl<S> WebsocketStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        WebsocketStream {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_eof: false,
+            flushing: None,
+            write_eof: false,
+        }
+    }
+}
+
+impl<S> Debug for WebsocketStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebsocketStream")
+            .field("buffered", &(self.read_buf.len() - self.read_pos))
+            .field("read_eof", &self.read_eof)
+            .finish()
+    }
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebsocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => this.read_eof = true,
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                // pings are answered by tungstenite, text is not ours
+                Some(Ok(Message::Close(_))) | None => this.read_eof = true,
+                Some(Ok(_)) => {}
+                Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => {
+                    this.read_eof = true
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebsocketStream<S> {
+    /// Each write is a message and isn't done until it's flushed, there is nobody
+    /// to flush it otherwise. A pending write is completed by the next call
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if this.flushing.is_none() {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io_error)?;
+            Pin::new(&mut this.inner)
+                .start_send(Message::Binary(buf.to_vec()))
+                .map_err(to_io_error)?;
+            this.flushing = Some(buf.len());
+        }
+        ready!(Pin::new(&mut this.inner).poll_flush(cx)).map_err(to_io_error)?;
+        Poll::Ready(Ok(this.flushing.take().unwrap_or_default()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.write_eof {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io_error)?;
+            Pin::new(&mut this.inner)
+                .start_send(Message::Binary(Vec::new()))
+                .map_err(to_io_error)?;
+            this.write_eof = true;
+        }
+        Pin::new(&mut this.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn pair() -> Result<(
+        WebsocketStream<MaybeTlsStream>,
+        WebsocketStream<MaybeTlsStream>,
+    )> {
+        let config = TransportConfig::default();
+        let transport = WebsocketTransport::new(&config).await?;
+        let listener = transport.bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let accept = async {
+            let (conn, _) = transport.accept(&listener).await?;
+            transport.handshake(conn).await
+        };
+        let (client, server) = tokio::join!(transport.connect(&addr), accept);
+        Ok((client?, server?))
+    }
+
+    #[tokio::test]
+    async fn test_bytes_over_messages() -> Result<()> {
+        let (mut client, mut server) = pair().await?;
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&data).await?;
+            client.shutdown().await?;
+            // the other direction stays open after shutting this one down
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await?;
+            anyhow::Ok(reply)
+        });
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await?;
+        assert_eq!(received, expected);
+        server.write_all(b"bye").await?;
+        server.shutdown().await?;
+
+        assert_eq!(writer.await??, b"bye");
+        Ok(())
+    }
+}
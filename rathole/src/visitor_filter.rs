@@ -0,0 +1,164 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::config::ServiceConfig;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Reject {
+    NotAllowed,
+    TooManyConnections,
+    TooFrequent,
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::NotAllowed => write!(f, "not allowed"),
+            Reject::TooManyConnections => write!(f, "too many connections"),
+            Reject::TooFrequent => write!(f, "too many new connections per second"),
+        }
+    }
+}
+
+/// Decides which visitors get through to a service, according to `allow`, `deny`,
+/// `max_connections` and `max_connections_per_sec` of its config
+pub struct VisitorFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    // a permit per connected visitor
+    connections: Option<Arc<Semaphore>>,
+    max_per_sec: Option<u32>,
+    window_start: Instant,
+    window_count: u32,
+    rejected: u64,
+}
+
+impl VisitorFilter {
+    pub fn new(config: &ServiceConfig) -> Self {
+        VisitorFilter {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            connections: config.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            max_per_sec: config.max_connections_per_sec,
+            window_start: Instant::now(),
+            window_count: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Only checks `allow` and `deny`
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // v4 visitors of a dual stack listener show up as v4-mapped v6 addresses
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Admit a new visitor, the returned permit must be held as long as it stays connected
+    pub fn admit(&mut self, ip: IpAddr) -> Result<Option<OwnedSemaphorePermit>, Reject> {
+        let res = self.do_admit(ip, Instant::now());
+        if res.is_err() {
+            self.rejected += 1;
+        }
+        res
+    }
+
+    /// Visitors rejected so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    fn do_admit(
+        &mut self,
+        ip: IpAddr,
+        now: Instant,
+    ) -> Result<Option<OwnedSemaphorePermit>, Reject> {
+        if !self.is_allowed(ip) {
+            return Err(Reject::NotAllowed);
+        }
+
+        let permit = match &self.connections {
+            Some(s) => Some(
+                s.clone()
+                    .try_acquire_owned()
+                    .map_err(|_| Reject::TooManyConnections)?,
+            ),
+            None => None,
+        };
+
+        if let Some(max) = self.max_per_sec {
+            if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+                self.window_start = now;
+                self.window_count = 0;
+            }
+            if self.window_count >= max {
+                return Err(Reject::TooFrequent);
+            }
+            self.window_count += 1;
+        }
+
+        Ok(permit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(f: impl FnOnce(&mut ServiceConfig)) -> VisitorFilter {
+        let mut config = ServiceConfig::default();
+        f(&mut config);
+        VisitorFilter::new(&config)
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let f = filter(|_| {});
+        assert!(f.is_allowed(ip("1.2.3.4")));
+
+        let f = filter(|c| {
+            c.allow = vec!["10.0.0.0/8".parse().unwrap()];
+            c.deny = vec!["10.1.0.0/16".parse().unwrap()];
+        });
+        assert!(f.is_allowed(ip("10.2.3.4")));
+        assert!(f.is_allowed(ip("::ffff:10.2.3.4")));
+        assert!(!f.is_allowed(ip("10.1.2.3")));
+        assert!(!f.is_allowed(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let mut f = filter(|c| c.max_connections = Some(1));
+        let ip = "1.2.3.4".parse().unwrap();
+
+        let permit = f.admit(ip).unwrap();
+        assert!(permit.is_some());
+        assert_eq!(f.admit(ip).unwrap_err(), Reject::TooManyConnections);
+
+        drop(permit);
+        assert!(f.admit(ip).is_ok());
+        assert_eq!(f.rejected(), 1);
+    }
+
+    #[test]
+    fn test_max_connections_per_sec() {
+        let mut f = filter(|c| c.max_connections_per_sec = Some(2));
+        let ip = "1.2.3.4".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(f.do_admit(ip, now).is_ok());
+        assert!(f.do_admit(ip, now).is_ok());
+        assert_eq!(f.do_admit(ip, now).unwrap_err(), Reject::TooFrequent);
+        assert!(f.do_admit(ip, now + Duration::from_secs(1)).is_ok());
+    }
+}
@@ -0,0 +1,919 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use rathole::{run, Cli};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use sha2::{Digest, Sha256};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::time;
+
+/// Write `ca.pem`, `server.pem` and `server.key` for `localhost` into `dir`
+fn generate_certs(dir: &Path) -> Result<()> {
+    let mut ca_params = CertificateParams::new(vec![]);
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "rathole test ca");
+    let ca = Certificate::from_params(ca_params)?;
+
+    let server = Certificate::from_params(CertificateParams::new(vec!["localhost".into()]))?;
+
+    fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    fs::write(
+        dir.join("server.pem"),
+        server.serialize_pem_with_signer(&ca)?,
+    )?;
+    fs::write(dir.join("server.key"), server.serialize_private_key_pem())?;
+    Ok(())
+}
+
+async fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port())
+}
+
+async fn run_echo_server(listener: TcpListener) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let (mut rd, mut wr) = conn.split();
+            let _ = io::copy(&mut rd, &mut wr).await;
+        });
+    }
+}
+
+async fn echo_through(addr: &str) -> Result<Vec<u8>> {
+    let mut conn = TcpStream::connect(addr).await?;
+    echo_over(&mut conn).await
+}
+
+/// Send `hello rathole` over `conn` and read it back
+async fn echo_over(conn: &mut TcpStream) -> Result<Vec<u8>> {
+    conn.write_all(b"hello rathole").await?;
+    let mut buf = vec![0u8; 13];
+    conn.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Keep trying until the tunnel is up or `timeout` runs out
+async fn wait_for_echo(addr: &str, timeout: Duration) -> Result<Vec<u8>> {
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        match time::timeout(Duration::from_secs(1), echo_through(addr)).await {
+            Ok(Ok(buf)) => return Ok(buf),
+            _ if time::Instant::now() < deadline => time::sleep(Duration::from_millis(200)).await,
+            Ok(Err(e)) => return Err(e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Forward connections from `listener` to `to` until `freeze` is notified, then keep the
+/// current ones open without moving a byte, like a NAT that silently dropped them
+async fn run_freezable_proxy(listener: TcpListener, to: String, freeze: Arc<Notify>) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        let to = to.clone();
+        let freeze = freeze.clone();
+        tokio::spawn(async move {
+            let mut upstream = match TcpStream::connect(&to).await {
+                Ok(c) => c,
+                Err(_) => return,
+            };
+            tokio::select! {
+                _ = io::copy_bidirectional(&mut conn, &mut upstream) => {},
+                _ = freeze.notified() => {
+                    std::future::pending::<()>().await;
+                }
+            }
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Transport {
+    Tcp,
+    Tls,
+    Websocket,
+    WebsocketTls,
+}
+
+/// Set the key at the dotted `path` of `config`, adding the tables on the way
+fn set(config: &mut toml::Value, path: &str, value: toml::Value) {
+    let mut keys: Vec<_> = path.split('.').collect();
+    let last = keys.pop().expect("an empty path");
+    let mut table = config.as_table_mut().expect("a config is a table");
+    for key in keys {
+        table = table
+            .entry(key.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .unwrap_or_else(|| panic!("{} is not a table", key));
+    }
+    table.insert(last.to_string(), value);
+}
+
+/// Remove the key at the dotted `path` of `config`
+fn unset(config: &mut toml::Value, path: &str) {
+    let (tables, last) = path.rsplit_once('.').expect("a key in a table");
+    let mut table = config.as_table_mut().expect("a config is a table");
+    for key in tables.split('.') {
+        table = table[key].as_table_mut().expect("a table");
+    }
+    table.remove(last);
+}
+
+/// A server and a client forwarding the `echo` service to `local_port`.
+/// Heartbeats are fast so dead channels are noticed within a few seconds.
+/// Tests change the configurations with `server` and `client` before starting
+struct Tunnel {
+    dir: tempfile::TempDir,
+    server_port: u16,
+    visitor_port: u16,
+    local_port: u16,
+    server: toml::Value,
+    client: toml::Value,
+}
+
+impl Tunnel {
+    async fn new(transport: Transport) -> Result<Tunnel> {
+        let dir = tempfile::tempdir()?;
+        generate_certs(dir.path())?;
+        let server_port = free_port().await?;
+        let visitor_port = free_port().await?;
+        let local_port = free_port().await?;
+
+        let server = format!(
+            r#"
+[server]
+bind_addr = "127.0.0.1:{}"
+default_token = "123456"
+heartbeat_interval = 1
+heartbeat_timeout = 3
+
+[server.services.echo]
+bind_addrs = ["127.0.0.1:{}"]
+"#,
+            server_port, visitor_port
+        );
+        let client = format!(
+            r#"
+[client]
+remote_addr = "127.0.0.1:{}"
+default_token = "123456"
+heartbeat_timeout = 3
+
+[client.services.echo]
+local_addr = "127.0.0.1:{}"
+"#,
+            server_port, local_port
+        );
+        let mut tunnel = Tunnel {
+            server: toml::from_str(&server)?,
+            client: toml::from_str(&client)?,
+            dir,
+            server_port,
+            visitor_port,
+            local_port,
+        };
+
+        let (transport_type, tls) = match transport {
+            Transport::Tcp => ("tcp", false),
+            Transport::Tls => ("tls", true),
+            Transport::Websocket => ("websocket", false),
+            Transport::WebsocketTls => ("websocket", true),
+        };
+        tunnel.both("transport.type", transport_type);
+        match transport {
+            Transport::Websocket => tunnel.both("transport.websocket.path", "/rathole"),
+            Transport::WebsocketTls => tunnel.both("transport.websocket.tls", true),
+            _ => {}
+        }
+        if tls {
+            let path = |name| tunnel.path(name).display().to_string();
+            let (cert, key, ca) = (path("server.pem"), path("server.key"), path("ca.pem"));
+            tunnel
+                .server("server.transport.tls.cert", cert)
+                .server("server.transport.tls.key", key)
+                .client("client.transport.tls.trusted_root", ca)
+                .client("client.transport.tls.hostname", "localhost");
+        }
+        Ok(tunnel)
+    }
+
+    /// Set `path` in the server configuration
+    fn server(&mut self, path: &str, value: impl Into<toml::Value>) -> &mut Self {
+        set(&mut self.server, path, value.into());
+        self
+    }
+
+    /// Set `path` in the client configuration
+    fn client(&mut self, path: &str, value: impl Into<toml::Value>) -> &mut Self {
+        set(&mut self.client, path, value.into());
+        self
+    }
+
+    /// Set `path` under `[server]` and `[client]`
+    fn both(&mut self, path: &str, value: impl Into<toml::Value> + Clone) {
+        self.server(&format!("server.{}", path), value.clone())
+            .client(&format!("client.{}", path), value);
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn server_addr(&self) -> String {
+        format!("127.0.0.1:{}", self.server_port)
+    }
+
+    fn visitor_addr(&self) -> String {
+        format!("127.0.0.1:{}", self.visitor_port)
+    }
+
+    /// Serve `echo` at `local_port`
+    async fn spawn_echo(&self) -> Result<()> {
+        let echo = TcpListener::bind(("127.0.0.1", self.local_port)).await?;
+        tokio::spawn(run_echo_server(echo));
+        Ok(())
+    }
+
+    fn start(&self) -> Result<broadcast::Sender<bool>> {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        self.start_server(shutdown_tx.clone())?;
+        self.start_client(shutdown_tx.clone())?;
+        Ok(shutdown_tx)
+    }
+
+    /// Write out the configurations, running instances reload them
+    fn write_configs(&self) -> Result<()> {
+        fs::write(self.path("server.toml"), toml::to_string(&self.server)?)?;
+        fs::write(self.path("client.toml"), toml::to_string(&self.client)?)?;
+        Ok(())
+    }
+
+    fn start_server(&self, shutdown_tx: broadcast::Sender<bool>) -> Result<()> {
+        let config_path = self.path("server.toml");
+        fs::write(&config_path, toml::to_string(&self.server)?)?;
+        let server = Cli {
+            config_path: Some(config_path),
+            server: true,
+            ..Default::default()
+        };
+        tokio::spawn(run(server, shutdown_tx));
+        Ok(())
+    }
+
+    fn start_client(&self, shutdown_tx: broadcast::Sender<bool>) -> Result<()> {
+        let config_path = self.path("client.toml");
+        fs::write(&config_path, toml::to_string(&self.client)?)?;
+        let client = Cli {
+            config_path: Some(config_path),
+            client: true,
+            ..Default::default()
+        };
+        tokio::spawn(run(client, shutdown_tx));
+        Ok(())
+    }
+}
+
+/// Start `tunnel` in front of an echo service and wait until it forwards
+async fn start_echo_tunnel(tunnel: &Tunnel) -> Result<broadcast::Sender<bool>> {
+    tunnel.spawn_echo().await?;
+    let shutdown_tx = tunnel.start()?;
+    let buf = wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(10)).await?;
+    assert_eq!(buf, b"hello rathole");
+    Ok(shutdown_tx)
+}
+
+#[tokio::test]
+async fn test_tls_forwarding() -> Result<()> {
+    let tunnel = Tunnel::new(Transport::Tls).await?;
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_untrusted_server() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tls).await?;
+    // a CA the server certificate is not signed by
+    let other = tempfile::tempdir()?;
+    generate_certs(other.path())?;
+    let ca = other.path().join("ca.pem").display().to_string();
+    tunnel.client("client.transport.tls.trusted_root", ca);
+
+    tunnel.spawn_echo().await?;
+    let shutdown_tx = tunnel.start()?;
+    assert!(
+        wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(3))
+            .await
+            .is_err()
+    );
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stalled_handshake() -> Result<()> {
+    for transport in [Transport::Tls, Transport::Websocket] {
+        let tunnel = Tunnel::new(transport).await?;
+        tunnel.spawn_echo().await?;
+        let (shutdown_tx, _) = broadcast::channel(1);
+        tunnel.start_server(shutdown_tx.clone())?;
+
+        // a peer that never gets through the handshake mustn't hold up the others
+        let _stalled = loop {
+            match TcpStream::connect(tunnel.server_addr()).await {
+                Ok(conn) => break conn,
+                Err(_) => time::sleep(Duration::from_millis(100)).await,
+            }
+        };
+        tunnel.start_client(shutdown_tx.clone())?;
+        let buf = wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(5)).await?;
+        assert_eq!(buf, b"hello rathole", "{:?}", transport);
+
+        let _ = shutdown_tx.send(true);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_forwarding() -> Result<()> {
+    for transport in [Transport::Websocket, Transport::WebsocketTls] {
+        let tunnel = Tunnel::new(transport).await?;
+        let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+        let _ = shutdown_tx.send(true);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_wrong_path() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Websocket).await?;
+    tunnel.client("client.transport.websocket.path", "/elsewhere");
+
+    tunnel.spawn_echo().await?;
+    let shutdown_tx = tunnel.start()?;
+    assert!(
+        wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(3))
+            .await
+            .is_err()
+    );
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+async fn run_udp_echo_server(socket: UdpSocket) {
+    let mut buf = vec![0u8; 1024];
+    while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+        let _ = socket.send_to(&buf[..n], from).await;
+    }
+}
+
+/// Send `payload` from `socket` to `addr` until it comes back, datagrams sent before
+/// the tunnel is up are lost
+async fn udp_echo_through(socket: &UdpSocket, addr: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 1024];
+    for _ in 0..50 {
+        socket.send_to(payload, addr).await?;
+        if let Ok(n) = time::timeout(Duration::from_millis(200), socket.recv(&mut buf)).await {
+            return Ok(buf[..n?].to_vec());
+        }
+    }
+    Err(anyhow::anyhow!("no echo from {}", addr))
+}
+
+#[tokio::test]
+async fn test_udp_forwarding() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    tunnel
+        .server("server.services.echo.type", "udp")
+        .client("client.services.echo.type", "udp");
+    let echo = UdpSocket::bind(("127.0.0.1", tunnel.local_port)).await?;
+    tokio::spawn(run_udp_echo_server(echo));
+    let shutdown_tx = tunnel.start()?;
+
+    // each visitor gets its own session and only its own datagrams back
+    let first = UdpSocket::bind("127.0.0.1:0").await?;
+    let second = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = tunnel.visitor_addr();
+    for _ in 0..10 {
+        for (visitor, payload) in [(&first, &b"first"[..]), (&second, &b"second"[..])] {
+            assert_eq!(udp_echo_through(visitor, &addr, payload).await?, payload);
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_reload() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    let local_addr = format!("127.0.0.1:{}", tunnel.local_port);
+    let gone_addr = format!("127.0.0.1:{}", free_port().await?);
+    tunnel
+        .server("server.services.gone.bind_addrs", vec![gone_addr.as_str()])
+        .client("client.services.gone.local_addr", local_addr.as_str());
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+    wait_for_echo(&gone_addr, Duration::from_secs(10)).await?;
+
+    // a visitor of the service the reload leaves alone
+    let mut visitor = TcpStream::connect(tunnel.visitor_addr()).await?;
+    assert_eq!(echo_over(&mut visitor).await?, b"hello rathole");
+
+    let added_addr = format!("127.0.0.1:{}", free_port().await?);
+    unset(&mut tunnel.server, "server.services.gone");
+    unset(&mut tunnel.client, "client.services.gone");
+    tunnel
+        .server(
+            "server.services.added.bind_addrs",
+            vec![added_addr.as_str()],
+        )
+        .client("client.services.added.local_addr", local_addr.as_str());
+    tunnel.write_configs()?;
+
+    let buf = wait_for_echo(&added_addr, Duration::from_secs(10)).await?;
+    assert_eq!(buf, b"hello rathole");
+    let deadline = time::Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(&gone_addr).await.is_ok() {
+        assert!(
+            time::Instant::now() < deadline,
+            "{} still listens",
+            gone_addr
+        );
+        time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(echo_over(&mut visitor).await?, b"hello rathole");
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_channel_requires_session_key() -> Result<()> {
+    let tunnel = Tunnel::new(Transport::Tcp).await?;
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    // the control channel is up, try to join it knowing only the service name
+    let mut conn = TcpStream::connect(tunnel.server_addr()).await?;
+    let hello = [
+        1u32.to_le_bytes().as_slice(), // Hello::DataChannel
+        Sha256::digest(b"echo").as_slice(),
+    ]
+    .concat();
+    conn.write_all(&frame(&hello)).await?;
+
+    let ack = read_frame(&mut conn).await?;
+    assert_eq!(ack, 2u32.to_le_bytes()); // Ack::AuthFailed
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+/// `[payload length: u32 big endian][payload]`
+fn frame(payload: &[u8]) -> Vec<u8> {
+    [(payload.len() as u32).to_be_bytes().as_slice(), payload].concat()
+}
+
+async fn read_frame(conn: &mut TcpStream) -> Result<Vec<u8>> {
+    let len = conn.read_u32().await?;
+    let mut payload = vec![0u8; len as usize];
+    conn.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+#[tokio::test]
+async fn test_incompatible_protocol_version() -> Result<()> {
+    let tunnel = Tunnel::new(Transport::Tcp).await?;
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    // a client from the future that dropped everything the server speaks
+    let mut conn = TcpStream::connect(tunnel.server_addr()).await?;
+    let hello = [
+        0u32.to_le_bytes().as_slice(),     // Hello::ControlChannel
+        9u16.to_le_bytes().as_slice(),     // version
+        9u16.to_le_bytes().as_slice(),     // min_version
+        u32::MAX.to_le_bytes().as_slice(), // capabilities
+        Sha256::digest(b"echo").as_slice(),
+    ]
+    .concat();
+    conn.write_all(&frame(&hello)).await?;
+
+    // the server still tells its versions, then hangs up
+    let reply = read_frame(&mut conn).await?;
+    assert_eq!(reply[..4], 0u32.to_le_bytes());
+    let version = u16::from_le_bytes([reply[4], reply[5]]);
+    assert!(version < 9);
+    let mut rest = vec![];
+    conn.read_to_end(&mut rest).await?;
+    assert!(rest.is_empty());
+
+    // the real client is unaffected
+    wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(10)).await?;
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unknown_hello_is_ignored() -> Result<()> {
+    let tunnel = Tunnel::new(Transport::Tcp).await?;
+    let (shutdown_tx, _) = broadcast::channel(1);
+    tunnel.start_server(shutdown_tx.clone())?;
+    time::sleep(Duration::from_millis(500)).await;
+
+    // a control channel of this version without capabilities, by hand
+    let mut conn = TcpStream::connect(tunnel.server_addr()).await?;
+    let hello = [
+        0u32.to_le_bytes().as_slice(), // Hello::ControlChannel
+        1u16.to_le_bytes().as_slice(), // version
+        1u16.to_le_bytes().as_slice(), // min_version
+        0u32.to_le_bytes().as_slice(), // capabilities
+        Sha256::digest(b"echo").as_slice(),
+    ]
+    .concat();
+    conn.write_all(&frame(&hello)).await?;
+    let reply = read_frame(&mut conn).await?;
+    let nonce = &reply[12..];
+    let auth = Sha256::digest([b"123456".as_slice(), nonce].concat());
+    conn.write_all(&frame(&auth)).await?;
+    assert_eq!(read_frame(&mut conn).await?, 0u32.to_le_bytes()); // Ack::Ok
+
+    // a hello of a newer client
+    conn.write_all(&frame(&99u32.to_le_bytes())).await?;
+    time::sleep(Duration::from_millis(500)).await;
+
+    // the control channel still asks for data channels
+    let _visitor = TcpStream::connect(tunnel.visitor_addr()).await?;
+    let cmd = time::timeout(Duration::from_secs(5), read_frame(&mut conn)).await??;
+    assert_eq!(cmd, 0u32.to_le_bytes()); // ControlChannelCmd::CreateDataChannel
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_reconnects_after_server_restart() -> Result<()> {
+    let tunnel = Tunnel::new(Transport::Tcp).await?;
+    tunnel.spawn_echo().await?;
+
+    let (client_shutdown_tx, _) = broadcast::channel(1);
+    tunnel.start_client(client_shutdown_tx.clone())?;
+
+    let (server_shutdown_tx, _) = broadcast::channel(1);
+    tunnel.start_server(server_shutdown_tx.clone())?;
+    wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(10)).await?;
+
+    let _ = server_shutdown_tx.send(true);
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(echo_through(&tunnel.visitor_addr()).await.is_err());
+
+    let (server_shutdown_tx, _) = broadcast::channel(1);
+    tunnel.start_server(server_shutdown_tx.clone())?;
+    let buf = wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(10)).await?;
+    assert_eq!(buf, b"hello rathole");
+
+    let _ = server_shutdown_tx.send(true);
+    let _ = client_shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat_timeout_replaces_dead_control_channel() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    tunnel.client("client.remote_addr", proxy.local_addr()?.to_string());
+    let freeze = Arc::new(Notify::new());
+    tokio::spawn(run_freezable_proxy(
+        proxy,
+        tunnel.server_addr(),
+        freeze.clone(),
+    ));
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    // the control channel stays open but nothing gets through anymore
+    freeze.notify_waiters();
+    time::sleep(Duration::from_millis(200)).await;
+
+    let buf = wait_for_echo(&tunnel.visitor_addr(), Duration::from_secs(15)).await?;
+    assert_eq!(buf, b"hello rathole");
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_v1() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    tunnel.client("client.services.echo.proxy_protocol", "v1");
+    let backend = TcpListener::bind(("127.0.0.1", tunnel.local_port)).await?;
+    let shutdown_tx = tunnel.start()?;
+
+    // the tunnel may take a few tries to come up, so does the visitor
+    let visitor_addr = tunnel.visitor_addr();
+    let visitor = tokio::spawn(async move {
+        loop {
+            if let Ok(mut conn) = TcpStream::connect(&visitor_addr).await {
+                let port = conn.local_addr()?.port();
+                conn.write_all(b"hello").await?;
+                return Ok::<_, anyhow::Error>((conn, port));
+            }
+            time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+
+    let (mut conn, _) = time::timeout(Duration::from_secs(10), backend.accept()).await??;
+    let (_visitor_conn, visitor_port) = visitor.await??;
+
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+        visitor_port, tunnel.visitor_port
+    );
+    let mut buf = vec![0u8; expected.len()];
+    conn.read_exact(&mut buf).await?;
+    assert_eq!(String::from_utf8(buf)?, expected);
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+async fn http_get(addr: &str, path: &str) -> Result<String> {
+    let mut conn = TcpStream::connect(addr).await?;
+    conn.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).as_bytes())
+        .await?;
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_admin_status_and_metrics() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    let admin_addr = format!("127.0.0.1:{}", free_port().await?);
+    tunnel.server("server.admin.bind_addr", admin_addr.as_str());
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    // keep a visitor connected
+    let mut visitor = TcpStream::connect(tunnel.visitor_addr()).await?;
+    visitor.write_all(b"hi").await?;
+    let mut buf = [0u8; 2];
+    visitor.read_exact(&mut buf).await?;
+
+    let status = http_get(&admin_addr, "/status").await?;
+    assert!(status.starts_with("HTTP/1.1 200 OK\r\n"));
+    let body = &status[status.find("\r\n\r\n").unwrap() + 4..];
+    let json: serde_json::Value = serde_json::from_str(body)?;
+    let echo = &json["services"][0];
+    assert_eq!(echo["name"], "echo");
+    assert_eq!(echo["control_channel"], true);
+    assert_eq!(echo["active_data_channels"], 1);
+    // "hello rathole" and "hi" both ways
+    assert_eq!(echo["bytes_in"], 15);
+    assert_eq!(echo["bytes_out"], 15);
+
+    let metrics = http_get(&admin_addr, "/metrics").await?;
+    assert!(metrics.contains("rathole_control_channel_up{service=\"echo\"} 1\n"));
+    assert!(metrics.contains("rathole_received_bytes_total{service=\"echo\"} 15\n"));
+
+    assert!(http_get(&admin_addr, "/nope")
+        .await?
+        .starts_with("HTTP/1.1 404"));
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+/// Answer every HTTP request with 200 and pass on its request line
+async fn run_http_stub(listener: TcpListener, requests: mpsc::UnboundedSender<String>) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![];
+            let mut chunk = [0u8; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                match conn.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&buf);
+            let _ = requests.send(request.lines().next().unwrap_or("").to_string());
+            let _ = conn
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await;
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_hooks() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    let stub = TcpListener::bind("127.0.0.1:0").await?;
+    let hook: toml::Value = toml::from_str(&format!(
+        "url = \"http://{}/{{event}}?service={{service}}&port={{port}}\"",
+        stub.local_addr()?
+    ))?;
+    tunnel.server("server.hooks", vec![hook]);
+    let (requests_tx, mut requests_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_http_stub(stub, requests_tx));
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    let mut requests = vec![];
+    for _ in 0..2 {
+        let request = time::timeout(Duration::from_secs(10), requests_rx.recv()).await?;
+        requests.push(request.unwrap());
+    }
+    requests.sort();
+    assert_eq!(
+        requests,
+        vec![
+            "GET /control_channel_up?service=echo&port= HTTP/1.1".to_string(),
+            format!(
+                "GET /service_bound?service=echo&port={} HTTP/1.1",
+                tunnel.visitor_port
+            ),
+        ]
+    );
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+/// Forward connections from `listener` to `to`, counting them
+async fn run_counting_proxy(listener: TcpListener, to: String, count: Arc<AtomicUsize>) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        count.fetch_add(1, Ordering::SeqCst);
+        let to = to.clone();
+        tokio::spawn(async move {
+            if let Ok(mut upstream) = TcpStream::connect(&to).await {
+                let _ = io::copy_bidirectional(&mut conn, &mut upstream).await;
+            }
+        });
+    }
+}
+
+/// Put a `run_counting_proxy` between the client and the server of `tunnel`
+async fn count_connections(tunnel: &mut Tunnel) -> Result<Arc<AtomicUsize>> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    tunnel.client("client.remote_addr", proxy.local_addr()?.to_string());
+    tokio::spawn(run_counting_proxy(
+        proxy,
+        tunnel.server_addr(),
+        connections.clone(),
+    ));
+    Ok(connections)
+}
+
+#[tokio::test]
+async fn test_multiplexed_forwarding() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tls).await?;
+    tunnel.client("client.multiplex.connections", 2);
+    let connections = count_connections(&mut tunnel).await?;
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+    // let the mux channels come up
+    time::sleep(Duration::from_millis(500)).await;
+    let before = connections.load(Ordering::SeqCst);
+
+    let visitors: Vec<_> = (0..20)
+        .map(|_| {
+            let addr = tunnel.visitor_addr();
+            tokio::spawn(async move { echo_through(&addr).await })
+        })
+        .collect();
+    for visitor in visitors {
+        assert_eq!(visitor.await??, b"hello rathole");
+    }
+
+    // every visitor rode on the existing mux channels
+    assert_eq!(connections.load(Ordering::SeqCst), before);
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_channel_pool() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tcp).await?;
+    tunnel.client("client.services.echo.pool_size", 3);
+    let connections = count_connections(&mut tunnel).await?;
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+    time::sleep(Duration::from_millis(500)).await;
+    // the control channel and the pool, whatever visitors used got replaced
+    let before = connections.load(Ordering::SeqCst);
+    assert!(before > 3);
+
+    // each visitor takes an idle channel and a new one refills the pool
+    for _ in 0..5 {
+        assert_eq!(
+            echo_through(&tunnel.visitor_addr()).await?,
+            b"hello rathole"
+        );
+    }
+    time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(connections.load(Ordering::SeqCst), before + 5);
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+/// An HTTP proxy counting the CONNECTs it serves
+async fn run_http_proxy_stub(listener: TcpListener, count: Arc<AtomicUsize>) {
+    while let Ok((mut conn, _)) = listener.accept().await {
+        let count = count.clone();
+        tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                match conn.read_u8().await {
+                    Ok(b) => request.push(b),
+                    Err(_) => return,
+                }
+            }
+            let request = String::from_utf8_lossy(&request);
+            let target = match request.strip_prefix("CONNECT ") {
+                Some(rest) => rest.split_whitespace().next().unwrap_or("").to_string(),
+                None => return,
+            };
+            if let Ok(mut upstream) = TcpStream::connect(&target).await {
+                count.fetch_add(1, Ordering::SeqCst);
+                if conn
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .is_ok()
+                {
+                    let _ = io::copy_bidirectional(&mut conn, &mut upstream).await;
+                }
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_client_behind_http_proxy() -> Result<()> {
+    let mut tunnel = Tunnel::new(Transport::Tls).await?;
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_url = format!("http://user:pass@{}", proxy.local_addr()?);
+    tunnel.client("client.transport.proxy", proxy_url);
+    let connects = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(run_http_proxy_stub(proxy, connects.clone()));
+    let shutdown_tx = start_echo_tunnel(&tunnel).await?;
+
+    // the control channel and at least one data channel went through the proxy
+    assert!(connects.load(Ordering::SeqCst) >= 2);
+
+    let _ = shutdown_tx.send(true);
+    Ok(())
+}
+
+fn rathole_check(config: &Path) -> Result<std::process::Output> {
+    Ok(std::process::Command::new(env!("CARGO_BIN_EXE_rathole"))
+        .arg("--check")
+        .arg(config)
+        .output()?)
+}
+
+#[test]
+fn test_check_valid_config() -> Result<()> {
+    let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/config_test/server.toml");
+    let output = rathole_check(&config)?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert_eq!(stdout, format!("{} is valid\n", config.display()));
+    Ok(())
+}
+
+#[test]
+fn test_check_reports_every_error() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config = dir.path().join("client.toml");
+    fs::write(
+        &config,
+        r#"
+[client]
+remote_addr = "example.com"
+
+[client.services.ssh]
+local_addr = "127.0.0.1:22"
+pool_size = 1000
+"#,
+    )?;
+    let output = rathole_check(&config)?;
+    assert!(!output.status.success());
+    let err = String::from_utf8(output.stderr)?;
+    for expected in [
+        "client.remote_addr: `example.com` has no port",
+        "client.services.ssh.pool_size: must be at most 64",
+        "client.services.ssh: the token is not set",
+    ] {
+        assert!(err.contains(expected), "{:?} not in {:?}", expected, err);
+    }
+    Ok(())
+}
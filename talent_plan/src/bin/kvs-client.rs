@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+use std::process;
+
+use clap::{App, AppSettings, Arg, SubCommand};
+
+use kvs::KvsClient;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    let addr = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .help("the address of the server")
+        .default_value(DEFAULT_ADDR)
+        .validator(|addr| {
+            addr.parse::<SocketAddr>()
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Talks to a kvs-server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommands(vec![
+            SubCommand::with_name("get")
+                .about("get the value bind by this key")
+                .args(&[
+                    Arg::with_name("KEY").help("the key").required(true),
+                    addr.clone(),
+                ]),
+            SubCommand::with_name("set")
+                .about("bind this value to the key")
+                .args(&[
+                    Arg::with_name("KEY").help("the key").required(true),
+                    Arg::with_name("VAL").help("the value").required(true),
+                    addr.clone(),
+                ]),
+            SubCommand::with_name("rm")
+                .about("remove a value by this key")
+                .args(&[Arg::with_name("KEY").help("the key").required(true), addr]),
+        ])
+        .get_matches();
+
+    let (name, args) = matches.subcommand();
+    let args = args.expect("a subcommand is required");
+    let key = args.value_of("KEY").expect("Key is not Exists").to_owned();
+
+    let result = KvsClient::connect(args.value_of("addr").expect("addr has a default")).and_then(
+        |mut client| match name {
+            "get" => client
+                .get(key)
+                .map(|val| println!("{}", val.unwrap_or_else(|| String::from("Key not found")))),
+            "set" => {
+                let val = args.value_of("VAL").expect("Val is not Exists").to_owned();
+                client.set(key, val)
+            }
+            "rm" => client.remove(key).map(|_| ()),
+            _ => unreachable!("clap only accepts the subcommands above"),
+        },
+    );
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
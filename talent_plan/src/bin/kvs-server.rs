@@ -0,0 +1,91 @@
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
+
+use clap::{App, Arg};
+use env_logger::Env;
+use log::info;
+
+use kvs::kvs::error::CliErr;
+use kvs::{select_engine, KvStore, KvsServer, MemStore, ThreadPool};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> Result<(), CliErr> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Serves a kvs store over the network")
+        .args(&[
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("the address to listen on")
+                .default_value(DEFAULT_ADDR)
+                .validator(|addr| {
+                    addr.parse::<SocketAddr>()
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                }),
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("the storage engine, the one the data was written by if omitted")
+                .possible_values(&["kvs", "memory"]),
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("connections served at the same time")
+                .default_value("1")
+                .validator(|n| match n.parse::<usize>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                }),
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("how long a connection may wait for a request before it's closed")
+                .default_value("5")
+                .validator(|secs| match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                }),
+        ])
+        .get_matches();
+
+    let addr = matches.value_of("addr").expect("addr has a default");
+    let threads = matches
+        .value_of("threads")
+        .and_then(|n| n.parse().ok())
+        .expect("threads has a valid default");
+    let idle_timeout = matches
+        .value_of("idle-timeout")
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .expect("idle-timeout has a valid default");
+    let dir = env::current_dir()?;
+    let engine = select_engine(&dir, matches.value_of("engine"), "kvs").unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {}", engine);
+    info!("Listening on {} with {} threads", addr, threads);
+
+    let pool = ThreadPool::new(threads)?;
+    match engine.as_str() {
+        "kvs" => KvsServer::new(KvStore::open(&dir)?, pool)
+            .read_timeout(idle_timeout)
+            .run(addr),
+        "memory" => KvsServer::new(MemStore::new(), pool)
+            .read_timeout(idle_timeout)
+            .run(addr),
+        name => {
+            eprintln!("Unknown engine {}", name);
+            process::exit(1);
+        }
+    }
+}
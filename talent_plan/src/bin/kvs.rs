@@ -5,18 +5,30 @@
 // to generate arguments dynamically.
 extern crate clap;
 
+use std::env;
+use std::ops::Bound;
+use std::path::Path;
 use std::process;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 use kvs::kvs::error::CliErr;
-use kvs::KvStore;
+use kvs::{select_engine, KvStore, KvsEngine, LogReport, MemStore, Pairs};
 
 fn main() -> Result<(), CliErr> {
     let matches = App::new("My kvs program")
         .version(env!("CARGO_PKG_VERSION"))
         .author("ping <ping@gmail.com>")
         .about("Does awesome things")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE-NAME")
+                .help("the storage engine, the one the data was written by if omitted")
+                .possible_values(&["kvs", "memory"])
+                .global(true),
+        )
         .subcommands(vec![
             SubCommand::with_name("get")
                 .about("get a value")
@@ -55,10 +67,58 @@ fn main() -> Result<(), CliErr> {
                         .required(true)
                         .index(1),
                 ),
+            SubCommand::with_name("scan")
+                .about("list the keys from START up to END and their values, in order")
+                .args(&[
+                    Arg::with_name("START")
+                        .help("the first key, from the first one if omitted")
+                        .index(1),
+                    Arg::with_name("END")
+                        .help("the key to stop before, to the last one if omitted")
+                        .index(2),
+                ]),
+            SubCommand::with_name("keys")
+                .about("list the keys, in order")
+                .arg(
+                    Arg::with_name("PREFIX")
+                        .help("only the keys starting with it")
+                        .index(1),
+                ),
+            SubCommand::with_name("check")
+                .about("look for damaged records in the log, exits with 1 if there are any"),
+            SubCommand::with_name("repair")
+                .about("drop the damaged records from the log, keeping the valid ones"),
         ])
         .get_matches();
 
-    let mut kv_store = KvStore::new()?;
+    let dir = env::current_dir()?;
+    let engine = select_engine(&dir, matches.value_of("engine"), "kvs").unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    match (engine.as_str(), matches.subcommand_name()) {
+        ("kvs", Some("check")) => return check(&dir),
+        ("kvs", Some("repair")) => return repair(&dir),
+        (name, Some("check")) | (name, Some("repair")) => {
+            println!("The {} engine keeps no log", name);
+            return Ok(());
+        }
+        _ => {}
+    }
+    match engine.as_str() {
+        "kvs" => run(KvStore::open(&dir)?, &matches),
+        "memory" => run(MemStore::new(), &matches),
+        name => {
+            eprintln!("Unknown engine {}", name);
+            process::exit(1);
+        }
+    }
+
+    // more program logic goes here...
+    Ok(())
+}
+
+fn run<E: KvsEngine>(kv_store: E, matches: &ArgMatches) {
     match matches.subcommand() {
         ("set", Some(set_args)) => {
             let key = set_args.value_of("KEY").expect("Key is not Exists");
@@ -81,12 +141,67 @@ fn main() -> Result<(), CliErr> {
                 process::exit(1);
             }
         }
+        ("scan", Some(scan_args)) => {
+            let bound = |name| match scan_args.value_of(name) {
+                Some(key) => Bound::Included(key.to_owned()),
+                None => Bound::Unbounded,
+            };
+            let end = match bound("END") {
+                Bound::Included(key) => Bound::Excluded(key),
+                unbounded => unbounded,
+            };
+            print_pairs(kv_store.scan((bound("START"), end)), |key, val| {
+                println!("{}\t{}", key, val)
+            });
+        }
+        ("keys", Some(keys_args)) => {
+            let prefix = keys_args.value_of("PREFIX").unwrap_or_default();
+            print_pairs(kv_store.prefix(prefix.to_owned()), |key, _| {
+                println!("{}", key)
+            });
+        }
         _ => {
             eprintln!("unimplemented");
             process::exit(1);
         }
     }
+}
 
-    // more program logic goes here...
+fn print_pairs<F: Fn(String, String)>(pairs: Pairs, print: F) {
+    for pair in pairs {
+        match pair {
+            Ok((key, val)) => print(key, val),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Print the damaged logs, exit with 1 if there are any
+fn check(dir: &Path) -> Result<(), CliErr> {
+    let damaged: Vec<_> = kvs::check(dir)?
+        .into_iter()
+        .filter(LogReport::is_damaged)
+        .collect();
+    if damaged.is_empty() {
+        println!("No damage found");
+        return Ok(());
+    }
+    for report in damaged {
+        println!("{}", report);
+    }
+    process::exit(1);
+}
+
+fn repair(dir: &Path) -> Result<(), CliErr> {
+    let repaired = kvs::repair(dir)?;
+    if repaired.is_empty() {
+        println!("Nothing to repair");
+    }
+    for report in repaired {
+        println!("Repaired {}", report);
+    }
     Ok(())
 }
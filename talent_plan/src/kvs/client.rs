@@ -0,0 +1,49 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use super::error::{CliErr, Result};
+use super::protocol::{read_message, write_message, Request, Response};
+
+/// A connection to a `KvsServer`
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Returns the value corresponding to the key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(&Request::Get { key })
+    }
+
+    /// Inserts a key-value pair into the store.
+    pub fn set(&mut self, key: String, val: String) -> Result<()> {
+        self.request(&Request::Set { key, val }).map(|_| ())
+    }
+
+    /// Removes a key from the store, returning the value at the key.
+    pub fn remove(&mut self, key: String) -> Result<String> {
+        self.request(&Request::Rm { key })?
+            .ok_or_else(|| CliErr::Server(String::from("No value removed")))
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        write_message(&mut self.writer, request)?;
+        match read_message(&mut self.reader)? {
+            Some(Response::Ok(value)) => Ok(value),
+            Some(Response::KeyNotFound) => Err(CliErr::KeyNotFound),
+            Some(Response::Err(msg)) => Err(CliErr::Server(msg)),
+            None => Err(CliErr::Server(String::from(
+                "The server closed the connection",
+            ))),
+        }
+    }
+}
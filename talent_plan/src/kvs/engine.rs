@@ -0,0 +1,111 @@
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use super::error::{CliErr, Result};
+use super::{sorted_gens, LEGACY_NAME};
+
+/// The file recording which engine wrote a directory
+const ENGINE_MARKER: &str = "engine";
+/// The engine writing logs into the directory
+const KVS_ENGINE: &str = "kvs";
+
+/// Key-value pairs in key order, each read when it's reached
+pub type Pairs = Box<dyn Iterator<Item = Result<(String, String)>>>;
+
+/// Owned bounds of a key range
+pub(crate) type Bounds = (Bound<String>, Bound<String>);
+
+/// A key-value storage backend. Clones share the same data, one per thread
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Returns the value corresponding to the key.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Inserts a key-value pair into the store.
+    fn set(&self, key: String, val: String) -> Result<()>;
+
+    /// Removes a key from the store, returning the value at the key if the key
+    /// was previously in the store.
+    fn remove(&self, key: String) -> Result<String>;
+
+    /// Returns the pairs with keys in `range`. Writes made while iterating
+    /// show up if they're ahead of it
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Pairs;
+
+    /// Returns the pairs with keys starting with `prefix`.
+    fn prefix(&self, prefix: String) -> Pairs {
+        Box::new(self.scan(prefix.clone()..).take_while(move |pair| {
+            pair.as_ref()
+                .map_or(true, |(key, _)| key.starts_with(&prefix))
+        }))
+    }
+}
+
+/// Walk `range` with `first`, which reads the first pair within some bounds.
+/// Nothing is borrowed between steps, so writers aren't held up by a slow reader
+pub(crate) fn step<R, F>(range: R, mut first: F) -> Pairs
+where
+    R: RangeBounds<String>,
+    F: FnMut(&Bounds) -> Result<Option<(String, String)>> + 'static,
+{
+    let mut bounds = Some((range.start_bound().cloned(), range.end_bound().cloned()));
+    Box::new(iter::from_fn(move || {
+        let current = bounds.take().filter(|bounds| !is_empty(bounds))?;
+        match first(&current) {
+            Ok(Some((key, val))) => {
+                bounds = Some((Bound::Excluded(key.clone()), current.1));
+                Some(Ok((key, val)))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }))
+}
+
+/// B-tree ranges panic on these instead of being empty
+fn is_empty(bounds: &Bounds) -> bool {
+    match bounds {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Returns the engine to open `dir` with: `requested` if given, otherwise the one
+/// that wrote `dir`, otherwise `default`. The kvs engine is recorded in `dir` so a
+/// different engine is refused later, logs from before it was recorded count too.
+/// The memory engine writes nothing, so it leaves no record
+pub fn select_engine(dir: &Path, requested: Option<&str>, default: &str) -> Result<String> {
+    let marker = dir.join(ENGINE_MARKER);
+    let recorded = match fs::read_to_string(&marker) {
+        Ok(name) => Some(name.trim().to_owned()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && has_logs(dir)? => {
+            Some(KVS_ENGINE.to_owned())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    match (recorded, requested) {
+        (Some(recorded), Some(requested)) if recorded != requested => Err(CliErr::WrongEngine {
+            recorded,
+            requested: requested.to_owned(),
+        }),
+        (Some(recorded), _) => Ok(recorded),
+        (None, requested) => {
+            let name = requested.unwrap_or(default).to_owned();
+            if name == KVS_ENGINE {
+                fs::write(&marker, &name)?;
+            }
+            Ok(name)
+        }
+    }
+}
+
+fn has_logs(dir: &Path) -> Result<bool> {
+    Ok(dir.join(LEGACY_NAME).is_file() || !sorted_gens(dir)?.is_empty())
+}
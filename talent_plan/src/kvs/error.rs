@@ -1,5 +1,6 @@
 use core::{result, fmt};
 use std::io;
+use std::ops::Range;
 use std::fmt::Formatter;
 
 pub type Result<T> = result::Result<T, CliErr>;
@@ -10,13 +11,44 @@ pub enum CliErr {
     BsonDeError(bson::de::Error),
     BsonSerError(bson::ser::Error),
     KeyNotFound,
+    /// The directory was written by another engine
+    WrongEngine {
+        recorded: String,
+        requested: String,
+    },
+    /// An error reported by the server
+    Server(String),
+    /// Bytes of the log of `gen` hold no valid record
+    Corrupted {
+        gen: u64,
+        ranges: Vec<Range<u64>>,
+    },
 }
 
 impl fmt::Display for CliErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match *self {
+        match self {
+            CliErr::IoError(err) => write!(f, "IO error: {}", err),
+            CliErr::BsonDeError(err) => write!(f, "Parse error: {}", err),
+            CliErr::BsonSerError(err) => write!(f, "Parse error: {}", err),
             CliErr::KeyNotFound => write!(f, "Key not found"),
-            _ => write!(f, "Parse error: {}", self),
+            CliErr::Server(msg) => write!(f, "{}", msg),
+            CliErr::WrongEngine {
+                recorded,
+                requested,
+            } => write!(
+                f,
+                "The data was written by the {} engine, can't open it with {}",
+                recorded, requested
+            ),
+            CliErr::Corrupted { gen, ranges } => {
+                write!(f, "{}.log is corrupted at bytes ", gen)?;
+                for (i, range) in ranges.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}..{}", sep, range.start, range.end)?;
+                }
+                write!(f, ", `kvs repair` drops the damaged records")
+            }
         }
     }
 }
@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::{Arc, Mutex};
+
+use super::engine::{self, KvsEngine, Pairs};
+use super::error::{CliErr, Result};
+
+/// A B-tree kept in memory only, everything is gone once the last clone is dropped
+#[derive(Clone, Default)]
+pub struct MemStore {
+    map: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        MemStore::default()
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.lock().unwrap().get(&key).cloned())
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.map.lock().unwrap().insert(key, val);
+        Ok(())
+    }
+
+    fn remove(&self, key: String) -> Result<String> {
+        self.map
+            .lock()
+            .unwrap()
+            .remove(&key)
+            .ok_or(CliErr::KeyNotFound)
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Pairs {
+        let map = Arc::clone(&self.map);
+        engine::step(range, move |bounds| {
+            let map = map.lock().unwrap();
+            let first = map.range(bounds.clone()).next();
+            Ok(first.map(|(key, val)| (key.clone(), val.clone())))
+        })
+    }
+}
@@ -1,26 +1,62 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::path;
+use std::cell::RefCell;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bson::Document;
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
 use serde::{Deserialize, Serialize};
 
 use error::{CliErr, Result};
 
+pub use client::KvsClient;
+pub use engine::{select_engine, KvsEngine, Pairs};
+pub use memory::MemStore;
+pub use record::{check, repair, LogReport};
+pub use server::KvsServer;
+pub use thread_pool::ThreadPool;
+
+mod client;
+mod engine;
 pub mod error;
+mod memory;
+mod protocol;
+mod record;
+mod server;
+mod thread_pool;
 
-const NAME: &str = "kvs";
+/// The single log file written before logs had generations
+const LEGACY_NAME: &str = "kvs";
 const COMPACT_THRESHOLD: u64 = 1024 * 1024;
+/// The extension of a compaction being written
+const COMPACTING: &str = "compacting";
 
-/// A in-memory Key-value store
+/// A log-structured key-value store, cheap to clone and share across threads.
+///
+/// Reads go through a lock-free index and a file handle per clone, writes and
+/// compaction take turns behind one lock
+#[derive(Clone)]
 pub struct KvStore {
-    map: HashMap<String, CommandPos>,
-    file: File,
-    uncompacted: u64,
+    index: Arc<Index>,
+    reader: LogReader,
+    writer: Arc<Mutex<LogWriter>>,
 }
 
+/// Positions are updated in place, replacing an entry would hide the key from
+/// readers for a moment
+type Index = SkipMap<String, AtomicCell<CommandPos>>;
+
+/// Where a record is: the generation of its log file, its offset and length
+#[derive(Clone, Copy, Debug)]
 struct CommandPos {
+    gen: u64,
     pos: u64,
     len: u64,
 }
@@ -33,132 +69,452 @@ enum Opt {
 
 impl KvStore {
     pub fn new() -> Result<Self> {
-        KvStore::open(path::Path::new(""))
-    }
-
-    /// create a new In-memory from the file
-    pub fn open(path: &path::Path) -> Result<KvStore> {
-        let mut map = HashMap::new();
-
-        let kvs_path = path.join(NAME);
-        let file = OpenOptions::new()
-            .read(true)
-            .create(true)
-            .append(true)
-            .open(kvs_path)?;
-
-        let mut read = BufReader::new(file);
-        let mut uncompact = 0u64;
-        let mut pos = read.stream_position()?;
-
-        while let Ok(d) = Document::from_reader(&mut read) {
-            let new_pos = read.stream_position()?;
-            let len = new_pos - pos;
-            let record = bson::from_document::<Opt>(d)?;
-            match record {
-                Opt::Set { key, .. } => {
-                    if let Some(old) = map.insert(key, CommandPos { pos, len }) {
-                        uncompact += old.len;
-                    }
-                }
-                Opt::Rm { key } => {
-                    map.remove(&key);
-                    uncompact += len;
-                }
-            }
-            pos = new_pos;
+        KvStore::open(Path::new(""))
+    }
+
+    /// Open the store in `path`, replaying every generation of the log.
+    ///
+    /// A torn write at the end of a log is truncated. Damage before valid
+    /// records fails with `CliErr::Corrupted` instead of losing them, `repair`
+    /// drops it
+    pub fn open(path: &Path) -> Result<KvStore> {
+        let path = Arc::new(path.to_path_buf());
+        migrate_legacy_log(&path)?;
+        remove_unfinished_compactions(&path)?;
+
+        let index = Arc::new(SkipMap::new());
+        let mut uncompacted = 0u64;
+        let gens = sorted_gens(&path)?;
+        for &gen in &gens {
+            uncompacted += load(&path, gen, &index)?;
         }
 
-        Ok(Self {
-            map,
-            file: read.into_inner(),
-            uncompacted: uncompact,
+        // writes go on at the end of the last generation, only a compaction
+        // starts a new one
+        let current_gen = gens.last().copied().unwrap_or(1);
+        let reader = LogReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        let writer = LogWriter {
+            reader: reader.clone(),
+            writer: new_log_file(&path, current_gen)?,
+            current_gen,
+            uncompacted,
+            path,
+            index: Arc::clone(&index),
+        };
+
+        Ok(KvStore {
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
         })
     }
 
-    /// Returns the value corresponding to the key.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.map.get(&key) {
-            Some(s) => {
-                self.file.seek(SeekFrom::Start(s.pos))?;
-                let doc = Document::from_reader(&mut self.file)?;
-                let record = bson::from_document::<Opt>(doc)?;
-                match record {
-                    Opt::Set { key: _, val } => Ok(Some(val)),
-                    _ => Ok(None),
+    /// Drop the overwritten and removed records now instead of waiting for
+    /// enough of them to pile up
+    pub fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    /// Read the value of what `lookup` finds, looking it up again if a
+    /// compaction moved it in between
+    fn read_value<K, F>(&self, lookup: F) -> Result<Option<(K, String)>>
+    where
+        F: Fn() -> Option<(K, CommandPos)>,
+    {
+        loop {
+            let (found, pos) = match lookup() {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            match self.reader.read_command(pos) {
+                Ok(Opt::Set { val, .. }) => return Ok(Some((found, val))),
+                Ok(Opt::Rm { .. }) => return Ok(None),
+                // compacted away between the lookup and the read, look it up again
+                Err(CliErr::IoError(e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && pos.gen < self.reader.safe_point.load(Ordering::SeqCst) =>
+                {
+                    continue
                 }
+                Err(e) => return Err(e),
             }
-            _ => Ok(None),
         }
     }
+}
 
-    /// Inserts a key-value pair into the store.
-    pub fn set(&mut self, key: String, val: String) -> Result<()> {
-        let pos = self.file.seek(SeekFrom::End(0))?;
-        let doc = bson::to_document(&Opt::Set {
+impl KvsEngine for KvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let found =
+            self.read_value(|| self.index.get(&key).map(|entry| ((), entry.value().load())))?;
+        Ok(found.map(|(_, val)| val))
+    }
+
+    fn set(&self, key: String, val: String) -> Result<()> {
+        self.writer.lock().unwrap().set(key, val)
+    }
+
+    fn remove(&self, key: String) -> Result<String> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    /// Values are read from the log a pair at a time
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Pairs {
+        let store = self.clone();
+        engine::step(range, move |bounds| {
+            store.read_value(|| {
+                let entry = store.index.range(bounds.clone()).next()?;
+                Some((entry.key().clone(), entry.value().load()))
+            })
+        })
+    }
+}
+
+/// Reads records through its own handles, one per generation. Handles to
+/// generations below `safe_point` are stale and closed on the next read
+struct LogReader {
+    path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+}
+
+impl Clone for LogReader {
+    fn clone(&self) -> Self {
+        LogReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl LogReader {
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        *readers = readers.split_off(&safe_point);
+    }
+
+    /// Hands `f` the bytes of the record at `pos`
+    fn read_and<F, R>(&self, pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReader<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(BufReader::new(File::open(log_path(&self.path, pos.gen))?))
+            }
+        };
+        reader.seek(SeekFrom::Start(pos.pos))?;
+        f(reader.take(pos.len))
+    }
+
+    fn read_command(&self, pos: CommandPos) -> Result<Opt> {
+        self.read_and(pos, |mut record| {
+            let mut bytes = Vec::with_capacity(pos.len as usize);
+            record.read_to_end(&mut bytes)?;
+            let mut payload = record::payload(&bytes).ok_or_else(|| CliErr::Corrupted {
+                gen: pos.gen,
+                ranges: vec![Range {
+                    start: pos.pos,
+                    end: pos.pos + pos.len,
+                }],
+            })?;
+            let doc = Document::from_reader(&mut payload)?;
+            Ok(bson::from_document(doc)?)
+        })
+    }
+}
+
+struct LogWriter {
+    reader: LogReader,
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    // bytes of records a compaction would drop
+    uncompacted: u64,
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+}
+
+impl LogWriter {
+    fn set(&mut self, key: String, val: String) -> Result<()> {
+        let pos = self.append(&Opt::Set {
             key: key.to_owned(),
             val,
         })?;
-        doc.to_writer(&mut self.file)?;
-        let new_pos = self.file.stream_position()?;
-        if let Some(old_command) = self.map.insert(
-            key,
-            CommandPos {
-                pos,
-                len: new_pos - pos,
-            },
-        ) {
-            self.uncompacted += old_command.len;
+        match self.index.get(&key) {
+            Some(entry) => self.uncompacted += entry.value().swap(pos).len,
+            None => {
+                self.index.insert(key, AtomicCell::new(pos));
+            }
         }
 
-        self.compact()?;
+        // the record is already written, a failed compaction is retried on the
+        // next one instead of failing this
+        if self.uncompacted >= COMPACT_THRESHOLD {
+            if let Err(e) = self.compact() {
+                log::error!("Failed to compact the log: {}", e);
+            }
+        }
         Ok(())
     }
 
+    fn remove(&mut self, key: String) -> Result<String> {
+        let old = match self.index.get(&key) {
+            Some(entry) => entry.value().load(),
+            None => return Err(CliErr::KeyNotFound),
+        };
+        let value = match self.reader.read_command(old)? {
+            Opt::Set { val, .. } => val,
+            Opt::Rm { .. } => return Err(CliErr::KeyNotFound),
+        };
+
+        let pos = self.append(&Opt::Rm {
+            key: key.to_owned(),
+        })?;
+        self.index.remove(&key);
+        // the removal itself is dropped by a compaction too
+        self.uncompacted += old.len + pos.len;
+
+        Ok(value)
+    }
+
+    fn append(&mut self, opt: &Opt) -> Result<CommandPos> {
+        let pos = self.writer.pos;
+        let mut payload = Vec::new();
+        bson::to_document(opt)?.to_writer(&mut payload)?;
+        record::write_record(&mut self.writer, &payload)?;
+        self.writer.flush()?;
+        Ok(CommandPos {
+            gen: self.current_gen,
+            pos,
+            len: self.writer.pos - pos,
+        })
+    }
+
+    /// Copy the live records into a new generation and delete the older ones.
+    /// It runs under the writer lock so writes wait for it, readers aren't
+    /// blocked. Later writes go to the generation after it.
+    ///
+    /// A crash at any step leaves a directory that opens to the same data: the
+    /// new generation only shows up complete and synced, and the older ones are
+    /// deleted oldest first afterwards, so a removal is never lost before the
+    /// value it removed
     fn compact(&mut self) -> Result<()> {
-        if self.uncompacted < COMPACT_THRESHOLD {
-            return Ok(());
-        }
+        let compaction_gen = self.current_gen + 1;
+        self.current_gen += 2;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
 
-        let mut buff = Vec::new();
-        for (_, command) in self.map.iter_mut() {
-            self.file.seek(SeekFrom::Start(command.pos))?;
-            let mut buf_vec = vec![0u8; command.len as usize];
-            self.file.read_exact(buf_vec.as_mut_slice())?;
+        // 1. copy the live records under a name that isn't a generation yet
+        let compaction_path = compaction_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(File::create(&compaction_path)?)?;
+        compaction_writer.write_all(record::MAGIC)?;
+        let mut moved = Vec::new();
+        for entry in self.index.iter() {
+            let start = compaction_writer.pos;
+            let len = self.reader.read_and(entry.value().load(), |mut record| {
+                Ok(io::copy(&mut record, &mut compaction_writer)?)
+            })?;
+            moved.push((entry, start, len));
+        }
+        compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
 
-            let pos = buff.len();
-            buff.append(&mut buf_vec);
-            command.pos = pos as u64;
+        // 2. swap it in
+        fs::rename(&compaction_path, log_path(&self.path, compaction_gen))?;
+        sync_dir(&self.path)?;
+        for (entry, pos, len) in moved {
+            entry.value().store(CommandPos {
+                gen: compaction_gen,
+                pos,
+                len,
+            });
         }
 
-        self.file.set_len(0)?;
-        self.file.write_all(&buff.as_slice())?;
+        // 3. delete the older generations, readers drop their handles to them
+        // before the next read
+        self.reader
+            .safe_point
+            .store(compaction_gen, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+        for gen in sorted_gens(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen)
+        {
+            if let Err(e) = fs::remove_file(log_path(&self.path, gen)) {
+                log::error!("Failed to remove the compacted log {}: {}", gen, e);
+            }
+        }
         self.uncompacted = 0;
         Ok(())
     }
+}
 
-    /// Removes a key from the store, returning the value at the key if the key
-    /// was previously in the store.
-    pub fn remove(&mut self, key: String) -> Result<String> {
-        let value = self.get(key.to_owned())?.ok_or(CliErr::KeyNotFound)?;
+/// Replay the log of `gen` into `index`, returns the bytes a compaction would drop.
+/// Logs from before checksums are rewritten with them first, unless that would
+/// drop records
+fn load(dir: &Path, gen: u64, index: &Index) -> Result<u64> {
+    let path = log_path(dir, gen);
+    let mut bytes = fs::read(&path)?;
+    let mut scan = record::scan(&bytes);
+    if !scan.corrupted.is_empty() {
+        return Err(CliErr::Corrupted {
+            gen,
+            ranges: scan.corrupted,
+        });
+    }
+    if scan.legacy {
+        if let Some(offset) = scan.torn_tail {
+            log::warn!(
+                "Dropping the unreadable bytes from {} on in {:?}",
+                offset,
+                path
+            );
+        }
+        record::rewrite(dir, gen, &bytes, &scan)?;
+        bytes = fs::read(&path)?;
+        scan = record::scan(&bytes);
+    }
+    if let Some(offset) = scan.torn_tail {
+        log::warn!("Truncating the torn write from {} on in {:?}", offset, path);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.set_len(offset)?;
+        file.sync_all()?;
+    }
 
-        self.file.seek(SeekFrom::End(0))?;
-        let doc = bson::to_document(&Opt::Rm {
-            key: key.to_owned(),
-        })?;
-        doc.to_writer(&mut self.file)?;
-        if let Some(rm) = self.map.remove(&key) {
-            self.uncompacted += rm.len;
+    let mut uncompacted = 0u64;
+    for (range, mut payload) in scan.records.iter().zip(scan.payloads(&bytes)) {
+        let Range { start: pos, end } = *range;
+        let len = end - pos;
+        match bson::from_document::<Opt>(Document::from_reader(&mut payload)?)? {
+            Opt::Set { key, .. } => {
+                let pos = CommandPos { gen, pos, len };
+                match index.get(&key) {
+                    Some(entry) => uncompacted += entry.value().swap(pos).len,
+                    None => {
+                        index.insert(key, AtomicCell::new(pos));
+                    }
+                }
+            }
+            Opt::Rm { key } => {
+                if let Some(old) = index.remove(&key) {
+                    uncompacted += old.value().load().len;
+                }
+                uncompacted += len;
+            }
         }
+    }
+    Ok(uncompacted)
+}
 
-        Ok(value)
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Where the compaction into `gen` is written until it's complete
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.{}", gen, COMPACTING))
+}
+
+/// Make renames and deletions in `dir` durable
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir_or_cwd(dir))?.sync_all()?;
+    Ok(())
+}
+
+/// Compactions interrupted before they were swapped in hold nothing new
+fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir_or_cwd(dir))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new(COMPACTING)) {
+            log::warn!("Removing the unfinished compaction {:?}", path);
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn dir_or_cwd(dir: &Path) -> &Path {
+    if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
     }
 }
 
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        if let Err(e) = self.file.flush() {
-            eprintln!("{}", e);
+/// The generations of the logs in `dir`, oldest first
+fn sorted_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir_or_cwd(dir))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("log")) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse().ok())
+            {
+                gens.push(gen);
+            }
         }
     }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// The log before generations becomes the first generation
+fn migrate_legacy_log(dir: &Path) -> Result<()> {
+    let legacy = dir.join(LEGACY_NAME);
+    if legacy.is_file() && sorted_gens(dir)?.is_empty() {
+        fs::rename(legacy, log_path(dir, 1))?;
+    }
+    Ok(())
+}
+
+fn new_log_file(dir: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(dir, gen))?;
+    let mut writer = BufWriterWithPos::new(file)?;
+    if writer.pos == 0 {
+        writer.write_all(record::MAGIC)?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// A buffered writer that knows how far into the file it is
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
 }
@@ -0,0 +1,45 @@
+use std::io::{BufRead, Write};
+
+use bson::{Bson, Document};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::error::Result;
+
+// Messages are wrapped in a document under this key, not all of them are documents
+const MESSAGE: &str = "msg";
+
+/// Every message is a single BSON document, which carries its own length
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Get { key: String },
+    Set { key: String, val: String },
+    Rm { key: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The value of `get` or `rm`, nothing for `set` and missing keys
+    Ok(Option<String>),
+    KeyNotFound,
+    /// Any other error, as the server displays it
+    Err(String),
+}
+
+pub fn write_message<W: Write, M: Serialize>(writer: &mut W, msg: &M) -> Result<()> {
+    let mut doc = Document::new();
+    doc.insert(MESSAGE, bson::to_bson(msg)?);
+    doc.to_writer(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns `None` once the peer has closed the connection between messages
+pub fn read_message<R: BufRead, M: DeserializeOwned>(reader: &mut R) -> Result<Option<M>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut doc = Document::from_reader(reader)?;
+    let msg = doc.remove(MESSAGE).unwrap_or(Bson::Null);
+    Ok(Some(bson::from_bson(msg)?))
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use bson::Document;
+
+use super::error::Result;
+use super::{compaction_path, log_path, migrate_legacy_log, sorted_gens, sync_dir, LEGACY_NAME};
+
+/// Starts every log whose records are checksummed
+pub const MAGIC: &[u8; 4] = b"KVS\x01";
+/// A record is its length and the CRC32 of its bytes, little endian, then the
+/// bytes themselves
+pub const HEADER_LEN: usize = 8;
+/// The smallest BSON document, anything shorter isn't a record
+const MIN_PAYLOAD: usize = 5;
+
+/// Write `payload` as a record
+pub fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// The payload of `record`, if it's one whole record with a matching checksum
+pub fn payload(record: &[u8]) -> Option<&[u8]> {
+    record_len(record, 0)
+        .filter(|&len| len == record.len())
+        .map(|_| &record[HEADER_LEN..])
+}
+
+/// The length of the valid record at `offset`, header included
+fn record_len(bytes: &[u8], offset: usize) -> Option<usize> {
+    let header = bytes.get(offset..offset + HEADER_LEN)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let payload = bytes.get(offset + HEADER_LEN..offset + HEADER_LEN + len)?;
+    (len >= MIN_PAYLOAD && crc32fast::hash(payload) == crc).then(|| HEADER_LEN + len)
+}
+
+/// The records of a log and the bytes between them that hold none
+pub struct Scan {
+    /// Written before records had checksums: bare BSON documents
+    pub legacy: bool,
+    /// Whole records, header included
+    pub records: Vec<Range<u64>>,
+    /// Damaged bytes with valid records after them
+    pub corrupted: Vec<Range<u64>>,
+    /// Where an incomplete last record starts, what a crash mid-write leaves
+    pub torn_tail: Option<u64>,
+}
+
+impl Scan {
+    pub fn payloads<'a>(&'a self, bytes: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let header = if self.legacy { 0 } else { HEADER_LEN };
+        self.records
+            .iter()
+            .map(move |range| &bytes[range.start as usize + header..range.end as usize])
+    }
+
+    fn is_damaged(&self) -> bool {
+        !self.corrupted.is_empty() || self.torn_tail.is_some()
+    }
+}
+
+/// Find the records in the bytes of a log. Damage is told from a torn write by
+/// looking for a valid record after it, checksums make a false match unlikely.
+///
+/// A log is only taken for one from before checksums if it starts with a
+/// document, damage to `MAGIC` is reported like any other
+pub fn scan(bytes: &[u8]) -> Scan {
+    if bytes.len() < MAGIC.len() && MAGIC.starts_with(bytes) {
+        return Scan {
+            legacy: false,
+            records: Vec::new(),
+            corrupted: Vec::new(),
+            torn_tail: Some(0).filter(|_| !bytes.is_empty()),
+        };
+    }
+    if bytes.starts_with(MAGIC) {
+        return scan_records(bytes, MAGIC.len(), record_len);
+    }
+    if legacy_len(bytes, 0).is_some() {
+        return Scan {
+            legacy: true,
+            ..scan_records(bytes, 0, legacy_len)
+        };
+    }
+
+    // the header is damaged, the records after it may not be
+    let mut scan = scan_records(bytes, MAGIC.len(), record_len);
+    if scan.records.is_empty() {
+        return Scan {
+            legacy: true,
+            ..scan_records(bytes, 0, legacy_len)
+        };
+    }
+    match scan.corrupted.first_mut() {
+        Some(range) if range.start == MAGIC.len() as u64 => range.start = 0,
+        _ => scan.corrupted.insert(0, 0..MAGIC.len() as u64),
+    }
+    scan
+}
+
+/// The records from `offset` on, `record_len` tells the length of the one at
+/// an offset if there's a valid one
+fn scan_records<F>(bytes: &[u8], mut offset: usize, record_len: F) -> Scan
+where
+    F: Fn(&[u8], usize) -> Option<usize>,
+{
+    let mut scan = Scan {
+        legacy: false,
+        records: Vec::new(),
+        corrupted: Vec::new(),
+        torn_tail: None,
+    };
+    while offset < bytes.len() {
+        if let Some(len) = record_len(bytes, offset) {
+            scan.records.push(offset as u64..(offset + len) as u64);
+            offset += len;
+            continue;
+        }
+        match (offset + 1..bytes.len()).find(|&next| record_len(bytes, next).is_some()) {
+            Some(next) => {
+                scan.corrupted.push(offset as u64..next as u64);
+                offset = next;
+            }
+            None => {
+                scan.torn_tail = Some(offset as u64);
+                break;
+            }
+        }
+    }
+    scan
+}
+
+/// The length of the bare BSON document at `offset`, logs from before
+/// checksums hold nothing else
+fn legacy_len(bytes: &[u8], offset: usize) -> Option<usize> {
+    let len = bytes.get(offset..offset + 4)?;
+    let len = i32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let mut doc = bytes.get(offset..offset.checked_add(len)?)?;
+    (len >= MIN_PAYLOAD && Document::from_reader(&mut doc).is_ok()).then(|| len)
+}
+
+/// Replace the log of `gen` with its valid records, checksummed. The new log is
+/// written aside and swapped in whole like a compaction
+pub fn rewrite(dir: &Path, gen: u64, bytes: &[u8], scan: &Scan) -> Result<()> {
+    let path = compaction_path(dir, gen);
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writer.write_all(MAGIC)?;
+    for payload in scan.payloads(bytes) {
+        write_record(&mut writer, payload)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(&path, log_path(dir, gen))?;
+    sync_dir(dir)
+}
+
+/// What `check` found in a log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogReport {
+    /// The file name of the log
+    pub log: String,
+    /// Valid records
+    pub records: usize,
+    /// Damaged bytes with valid records after them, `KvStore::open` refuses
+    /// these
+    pub corrupted: Vec<Range<u64>>,
+    /// Where an incomplete last record starts, `KvStore::open` truncates it
+    pub torn_tail: Option<u64>,
+}
+
+impl LogReport {
+    fn new(log: String, scan: &Scan) -> Self {
+        LogReport {
+            log,
+            records: scan.records.len(),
+            corrupted: scan.corrupted.clone(),
+            torn_tail: scan.torn_tail,
+        }
+    }
+
+    pub fn is_damaged(&self) -> bool {
+        !self.corrupted.is_empty() || self.torn_tail.is_some()
+    }
+}
+
+impl fmt::Display for LogReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} valid records", self.log, self.records)?;
+        for range in &self.corrupted {
+            write!(f, ", bytes {}..{} corrupted", range.start, range.end)?;
+        }
+        if let Some(offset) = self.torn_tail {
+            write!(f, ", torn write from byte {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Scan every log in `dir` without changing anything, the log from before
+/// generations included
+pub fn check(dir: &Path) -> Result<Vec<LogReport>> {
+    let mut reports = Vec::new();
+    let gens = sorted_gens(dir)?;
+    let legacy = dir.join(LEGACY_NAME);
+    if gens.is_empty() && legacy.is_file() {
+        let bytes = fs::read(legacy)?;
+        reports.push(LogReport::new(LEGACY_NAME.to_owned(), &scan(&bytes)));
+    }
+    for gen in gens {
+        let bytes = fs::read(log_path(dir, gen))?;
+        reports.push(LogReport::new(log_name(gen), &scan(&bytes)));
+    }
+    Ok(reports)
+}
+
+/// Drop the damaged bytes from every log in `dir`, keeping all valid records.
+/// The log from before generations becomes the first one like on open. Returns
+/// what was found in the logs it rewrote
+pub fn repair(dir: &Path) -> Result<Vec<LogReport>> {
+    migrate_legacy_log(dir)?;
+    let mut repaired = Vec::new();
+    for gen in sorted_gens(dir)? {
+        let bytes = fs::read(log_path(dir, gen))?;
+        let scan = scan(&bytes);
+        if scan.is_damaged() {
+            rewrite(dir, gen, &bytes, &scan)?;
+            repaired.push(LogReport::new(log_name(gen), &scan));
+        }
+    }
+    Ok(repaired)
+}
+
+fn log_name(gen: u64) -> String {
+    format!("{}.log", gen)
+}
@@ -0,0 +1,93 @@
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use log::{debug, error};
+
+use super::engine::KvsEngine;
+use super::error::{CliErr, Result};
+use super::protocol::{read_message, write_message, Request, Response};
+use super::thread_pool::ThreadPool;
+
+/// How long a connection may wait for its next request by default
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves an engine over tcp, a connection per thread of the pool at a time.
+/// Idle connections are closed after the read timeout so they don't keep the
+/// threads from the others
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+    pool: ThreadPool,
+    read_timeout: Duration,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    pub fn new(engine: E, pool: ThreadPool) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            read_timeout: READ_TIMEOUT,
+        }
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    pub fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = stream.set_read_timeout(Some(self.read_timeout)) {
+                        error!("Failed to set the read timeout: {}", e);
+                        continue;
+                    }
+                    let engine = self.engine.clone();
+                    self.pool.spawn(move || {
+                        if let Err(e) = serve(engine, stream) {
+                            error!("Error on serving client: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match read_message::<_, Request>(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(CliErr::IoError(e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                debug!("Closing the idle connection from {}", peer);
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Request from {}: {:?}", peer, request);
+        let result = match request {
+            Request::Get { key } => engine.get(key),
+            Request::Set { key, val } => engine.set(key, val).map(|_| None),
+            Request::Rm { key } => engine.remove(key).map(Some),
+        };
+        let response = match result {
+            Ok(value) => Response::Ok(value),
+            Err(CliErr::KeyNotFound) => Response::KeyNotFound,
+            Err(e) => Response::Err(e.to_string()),
+        };
+        debug!("Response to {}: {:?}", peer, response);
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
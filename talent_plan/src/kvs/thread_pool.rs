@@ -0,0 +1,73 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::error;
+
+use super::error::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads taking jobs from one shared queue. A panicking job
+/// doesn't take its thread down
+pub struct ThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("kvs-worker-{}", i))
+                    .spawn(move || run_jobs(receiver))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(ThreadPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .as_ref()
+            .expect("the sender lives as long as the pool")
+            .send(Box::new(job))
+            .expect("the workers outlive the pool");
+    }
+}
+
+fn run_jobs(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // the lock is released before the job runs
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => {
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("A job of the thread pool panicked");
+                }
+            }
+            // the pool is dropped
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Finish the queued jobs
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,4 +1,7 @@
 pub mod kvs;
 
-pub use crate::kvs::KvStore;
+pub use crate::kvs::{
+    check, repair, select_engine, KvStore, KvsClient, KvsEngine, KvsServer, LogReport, MemStore,
+    Pairs, ThreadPool,
+};
 pub use crate::kvs::error::Result;
\ No newline at end of file
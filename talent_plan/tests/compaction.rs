@@ -0,0 +1,188 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+// A compaction is interrupted by copying the files it leaves behind at each step
+// next to the ones from before it started, then reopening.
+
+fn files(dir: &Path) -> BTreeSet<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect()
+}
+
+fn copy_files(from: &Path, to: &Path, names: &BTreeSet<String>) {
+    for name in names {
+        fs::copy(from.join(name), to.join(name)).unwrap();
+    }
+}
+
+fn gen_of(name: &str) -> u64 {
+    name.trim_end_matches(".log").parse().unwrap()
+}
+
+fn new_gen(dir: &Path, gen: u64) {
+    File::create(dir.join(format!("{}.log", gen))).unwrap();
+}
+
+fn assert_contents(dir: &Path, expected: &HashMap<String, Option<String>>) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    for (key, value) in expected {
+        assert_eq!(&store.get(key.to_owned())?, value, "{}", key);
+    }
+    Ok(())
+}
+
+/// The directory before and after a compaction of overwritten and removed keys,
+/// and what it holds
+struct Compacted {
+    before: TempDir,
+    after: TempDir,
+    expected: HashMap<String, Option<String>>,
+}
+
+fn compacted() -> Result<Compacted> {
+    let before = TempDir::new().expect("unable to create temporary working directory");
+    let after = TempDir::new().expect("unable to create temporary working directory");
+    let mut expected = HashMap::new();
+
+    // a generation per round, opening appends to the last one so each is
+    // started by creating its log
+    for iter in 0..3 {
+        new_gen(before.path(), iter + 1);
+        let store = KvStore::open(before.path())?;
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            let value = format!("value{}_{}", key_id, iter);
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, Some(value));
+        }
+    }
+    // the removals in a newer generation than the values they remove
+    new_gen(before.path(), 4);
+    let store = KvStore::open(before.path())?;
+    for key_id in (0..100).step_by(3) {
+        let key = format!("key{}", key_id);
+        store.remove(key.clone())?;
+        expected.insert(key, None);
+    }
+    drop(store);
+    assert_eq!(files(before.path()).len(), 4);
+
+    copy_files(before.path(), after.path(), &files(before.path()));
+    KvStore::open(after.path())?.compact()?;
+    Ok(Compacted {
+        before,
+        after,
+        expected,
+    })
+}
+
+// Opening leaves the files alone, writes go on in the last generation.
+#[test]
+fn reopening_keeps_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(String::from("key1"), String::from("value1"))?;
+    drop(store);
+    let written = files(temp_dir.path());
+
+    drop(KvStore::open(temp_dir.path())?);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get(String::from("key1"))?,
+        Some(String::from("value1"))
+    );
+    drop(store);
+    assert_eq!(files(temp_dir.path()), written);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(String::from("key2"), String::from("value2"))?;
+    drop(store);
+    assert_eq!(files(temp_dir.path()), written);
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_contents() -> Result<()> {
+    let c = compacted()?;
+    assert_contents(c.after.path(), &c.expected)?;
+
+    let gone: Vec<_> = files(c.before.path())
+        .difference(&files(c.after.path()))
+        .cloned()
+        .collect();
+    assert!(!gone.is_empty(), "the old generations are deleted");
+    Ok(())
+}
+
+// Interrupted while copying: a partial compaction is ignored and cleaned up.
+#[test]
+fn interrupted_while_copying() -> Result<()> {
+    let c = compacted()?;
+    let new = files(c.after.path());
+    let compacted = new
+        .difference(&files(c.before.path()))
+        .min_by_key(|name| gen_of(name))
+        .unwrap()
+        .clone();
+
+    let partial = c.before.path().join(format!("{}.compacting", compacted));
+    fs::copy(c.after.path().join(&compacted), &partial)?;
+    let len = fs::metadata(&partial)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&partial)?
+        .set_len(len / 2)?;
+
+    assert_contents(c.before.path(), &c.expected)?;
+    assert!(!partial.exists());
+    Ok(())
+}
+
+// Interrupted after the swap, before any old generation is deleted.
+#[test]
+fn interrupted_before_deleting() -> Result<()> {
+    let c = compacted()?;
+    let new: BTreeSet<_> = files(c.after.path())
+        .difference(&files(c.before.path()))
+        .cloned()
+        .collect();
+    copy_files(c.after.path(), c.before.path(), &new);
+
+    assert_contents(c.before.path(), &c.expected)?;
+    // and once more after writing on top of it
+    let store = KvStore::open(c.before.path())?;
+    store.set(String::from("key1"), String::from("later"))?;
+    drop(store);
+    let mut expected = c.expected;
+    expected.insert(String::from("key1"), Some(String::from("later")));
+    assert_contents(c.before.path(), &expected)
+}
+
+// Interrupted while deleting the old generations, oldest first: the removals
+// in the last one outlive the values they removed.
+#[test]
+fn interrupted_while_deleting() -> Result<()> {
+    let c = compacted()?;
+    let old: Vec<_> = files(c.before.path()).into_iter().collect();
+    let new: BTreeSet<_> = files(c.after.path())
+        .difference(&files(c.before.path()))
+        .cloned()
+        .collect();
+    copy_files(c.after.path(), c.before.path(), &new);
+
+    let mut old: Vec<_> = old
+        .into_iter()
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    old.sort_by_key(|name| gen_of(name));
+    for name in old {
+        fs::remove_file(c.before.path().join(name))?;
+        assert_contents(c.before.path(), &c.expected)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use assert_cmd::prelude::*;
+use bson::doc;
+use kvs::kvs::error::CliErr;
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Ten keys in the first generation
+fn written() -> Result<(TempDir, PathBuf)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    Ok((temp_dir, log))
+}
+
+fn flip_byte(log: &Path, offset: usize) {
+    let mut bytes = fs::read(log).unwrap();
+    bytes[offset] ^= 0xff;
+    fs::write(log, bytes).unwrap();
+}
+
+fn assert_keys(dir: &Path, present: impl Fn(usize) -> bool) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    for key_id in 0..10 {
+        let expected = Some(format!("value{}", key_id)).filter(|_| present(key_id));
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            expected,
+            "key{}",
+            key_id
+        );
+    }
+    Ok(())
+}
+
+// A record cut short by a crash is dropped on open, the rest is kept.
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let (temp_dir, log) = written()?;
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    assert_keys(temp_dir.path(), |key_id| key_id != 9)?;
+    assert!(fs::metadata(&log)?.len() < len - 3);
+
+    // and the log takes more writes after it
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(String::from("key9"), String::from("value9"))?;
+    drop(store);
+    assert_keys(temp_dir.path(), |_| true)
+}
+
+// Damage before valid records fails the open with where it is.
+#[test]
+fn corruption_is_reported() -> Result<()> {
+    let (temp_dir, log) = written()?;
+    let len = fs::metadata(&log)?.len() as usize;
+    flip_byte(&log, len / 2);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(CliErr::Corrupted { gen, ranges }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(ranges.len(), 1);
+            assert!(ranges[0].contains(&(len as u64 / 2)));
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("opened a corrupted log"),
+    }
+
+    let reports = kvs::check(temp_dir.path())?;
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].is_damaged());
+    assert_eq!(reports[0].records, 9);
+    assert_eq!(reports[0].torn_tail, None);
+    Ok(())
+}
+
+// A damaged header doesn't pass the log off as one from before checksums.
+#[test]
+fn damaged_header_is_reported() -> Result<()> {
+    let (temp_dir, log) = written()?;
+    let len = fs::metadata(&log)?.len();
+    flip_byte(&log, 0);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(CliErr::Corrupted { gen, ranges }) => {
+            assert_eq!(gen, 1);
+            assert_eq!(ranges, vec![0..4]);
+        }
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("opened a corrupted log"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), len);
+
+    let reports = kvs::check(temp_dir.path())?;
+    assert!(reports[0].is_damaged());
+    assert_eq!(reports[0].records, 10);
+
+    kvs::repair(temp_dir.path())?;
+    assert_keys(temp_dir.path(), |_| true)
+}
+
+#[test]
+fn repair_keeps_valid_records() -> Result<()> {
+    let (temp_dir, log) = written()?;
+    let len = fs::metadata(&log)?.len() as usize;
+    flip_byte(&log, len / 2);
+
+    let repaired = kvs::repair(temp_dir.path())?;
+    assert_eq!(repaired.len(), 1);
+    assert!(kvs::check(temp_dir.path())?
+        .iter()
+        .all(|report| !report.is_damaged()));
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut missing = 0;
+    for key_id in 0..10 {
+        match store.get(format!("key{}", key_id))? {
+            Some(value) => assert_eq!(value, format!("value{}", key_id)),
+            None => missing += 1,
+        }
+    }
+    assert_eq!(missing, 1);
+    Ok(())
+}
+
+// Logs written before records had checksums are rewritten with them.
+#[test]
+fn legacy_log_is_checksummed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut bytes = Vec::new();
+    doc! { "Set": { "key": "key1", "val": "value1" } }.to_writer(&mut bytes)?;
+    doc! { "Set": { "key": "key2", "val": "value2" } }.to_writer(&mut bytes)?;
+    doc! { "Rm": { "key": "key1" } }.to_writer(&mut bytes)?;
+    fs::write(temp_dir.path().join("1.log"), &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(String::from("key1"))?, None);
+    assert_eq!(
+        store.get(String::from("key2"))?,
+        Some(String::from("value2"))
+    );
+    drop(store);
+
+    let reports = kvs::check(temp_dir.path())?;
+    assert_eq!(reports[0].records, 3);
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVS"));
+    Ok(())
+}
+
+#[test]
+fn cli_check_and_repair() -> Result<()> {
+    let (temp_dir, log) = written()?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("No damage found"));
+
+    let len = fs::metadata(&log)?.len() as usize;
+    flip_byte(&log, len / 2);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("1.log: 9 valid records, bytes"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Repaired 1.log"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["check"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value0"));
+    Ok(())
+}
+
+// The log from before generations is checked and repaired too.
+#[test]
+fn legacy_log_is_checked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut bytes = Vec::new();
+    doc! { "Set": { "key": "key1", "val": "value1" } }.to_writer(&mut bytes)?;
+    doc! { "Set": { "key": "key2", "val": "value2" } }.to_writer(&mut bytes)?;
+    // and the start of a third, cut off
+    let torn = bytes[..10].to_vec();
+    bytes.extend_from_slice(&torn);
+    fs::write(temp_dir.path().join("kvs"), &bytes)?;
+
+    let reports = kvs::check(temp_dir.path())?;
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].log, "kvs");
+    assert_eq!(reports[0].records, 2);
+    assert!(reports[0].torn_tail.is_some());
+
+    let repaired = kvs::repair(temp_dir.path())?;
+    assert_eq!(repaired.len(), 1);
+    assert!(kvs::check(temp_dir.path())?
+        .iter()
+        .all(|report| !report.is_damaged()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get(String::from("key2"))?,
+        Some(String::from("value2"))
+    );
+    Ok(())
+}
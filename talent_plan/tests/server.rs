@@ -0,0 +1,218 @@
+use assert_cmd::prelude::*;
+use kvs::KvsClient;
+use predicates::ord::eq;
+use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Kills the server when the test is done.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn start_server(dir: &Path, addr: &str, engine: &str) -> Server {
+    start_server_with(dir, addr, &["--engine", engine])
+}
+
+fn start_server_with(dir: &Path, addr: &str, args: &[&str]) -> Server {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let server = Server(child);
+    for _ in 0..50 {
+        if TcpStream::connect(addr).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("kvs-server didn't start listening on {}", addr);
+}
+
+fn client(addr: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    cmd.args(args).args(["--addr", addr]);
+    cmd
+}
+
+#[test]
+fn client_cli_invalid() {
+    Command::cargo_bin("kvs-client").unwrap().assert().failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "not an address"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "missing_value"])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_cli_invalid() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "unknown"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1"])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .assert()
+        .failure();
+}
+
+// The server should refuse data written by another engine.
+#[test]
+fn server_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    drop(start_server(temp_dir.path(), &addr, "kvs"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("written by the kvs engine"));
+}
+
+fn access_server(engine: &str) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let server = start_server(temp_dir.path(), &addr, engine);
+
+    client(&addr, &["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    client(&addr, &["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    client(&addr, &["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    client(&addr, &["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    client(&addr, &["rm", "key1"])
+        .assert()
+        .failure()
+        .stderr(eq("Key not found").trim());
+
+    client(&addr, &["set", "key2", "value2"]).assert().success();
+    drop(server);
+    if engine == "memory" {
+        return;
+    }
+
+    // The data outlives the server.
+    let _server = start_server(temp_dir.path(), &addr, engine);
+    client(&addr, &["get", "key2"])
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+}
+
+#[test]
+fn client_server_kvs() {
+    access_server("kvs");
+}
+
+#[test]
+fn client_server_memory() {
+    access_server("memory");
+}
+
+// Clients are served at the same time by a pool of threads.
+#[test]
+fn client_server_threads() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    let _server = start_server_with(temp_dir.path(), &addr, &["--threads", "4"]);
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let key = format!("key{}", i);
+                let value = format!("value{}", i);
+                client(&addr, &["set", &key, &value]).assert().success();
+                client(&addr, &["get", &key])
+                    .assert()
+                    .success()
+                    .stdout(eq(value.as_str()).trim());
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// A connection that sends nothing is closed instead of keeping the only thread.
+#[test]
+fn idle_connection_times_out() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = free_addr();
+    // one thread, which the idle connection takes
+    let args = ["--engine", "memory", "--idle-timeout", "1"];
+    let _server = start_server_with(temp_dir.path(), &addr, &args);
+    let _idle = TcpStream::connect(&addr).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let client_addr = addr.clone();
+    thread::spawn(move || {
+        let result = KvsClient::connect(&client_addr).and_then(|mut client| {
+            client.set(String::from("key1"), String::from("value1"))?;
+            client.get(String::from("key1"))
+        });
+        let _ = tx.send(result.map_err(|e| e.to_string()));
+    });
+    let value = rx
+        .recv_timeout(Duration::from_secs(10))
+        .expect("the idle connection kept the server busy");
+    assert_eq!(value, Ok(Some(String::from("value1"))));
+}
+
+#[test]
+fn client_without_server() {
+    client(&free_addr(), &["get", "key1"]).assert().failure();
+}
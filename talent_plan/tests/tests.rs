@@ -1,8 +1,11 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsEngine, MemStore, Result, ThreadPool};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +68,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -94,7 +97,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -168,6 +171,34 @@ fn cli_invalid_rm() {
         .failure();
 }
 
+// `kvs --engine <ENGINE>` should refuse data written by another engine.
+#[test]
+fn cli_wrong_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("written by the kvs engine"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
+
 #[test]
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
@@ -181,7 +212,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +222,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +233,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +242,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +254,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +270,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +278,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +290,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -289,7 +320,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +330,287 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// The in-memory engine behaves like the log.
+#[test]
+fn memory_engine() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.remove("key1".to_owned())?, "value2".to_owned());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Clones of a store share it across threads.
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}_{}", t, i);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            let key = format!("key{}_{}", t, i);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Readers see consistent values while a writer keeps triggering compactions.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), String::from("0"))?;
+    }
+
+    // the readers keep going until the writer is done, so they race every
+    // compaction it triggers
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                let mut round = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let value = store.get(format!("key{}", round % 100))?;
+                    assert!(value.is_some());
+                    round += 1;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for iter in 1..200 {
+        for key_id in 0..100 {
+            // long enough values to go through a few compactions
+            store.set(format!("key{}", key_id), format!("{:0>100}", iter))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    // the first generation is only ever deleted by a compaction
+    assert!(!temp_dir.path().join("1.log").exists());
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{:0>100}", 199))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn thread_pool_survives_panics() -> Result<()> {
+    let pool = ThreadPool::new(2)?;
+    for _ in 0..4 {
+        pool.spawn(|| panic!("a failing job"));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    for i in 0..8 {
+        let tx = tx.clone();
+        pool.spawn(move || tx.send(i).unwrap());
+    }
+    drop(tx);
+    let mut done: Vec<i32> = rx.iter().collect();
+    done.sort_unstable();
+    assert_eq!(done, (0..8).collect::<Vec<_>>());
+    Ok(())
+}
+
+fn scan_and_prefix<E: KvsEngine>(store: E) -> Result<()> {
+    for key in ["b", "a", "ab", "abc", "b1", "c"] {
+        store.set(key.to_owned(), format!("{}_value", key))?;
+    }
+    store.remove("b1".to_owned())?;
+
+    let keys = |pairs: kvs::Pairs| -> Result<Vec<String>> {
+        pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(keys(store.scan(..))?, ["a", "ab", "abc", "b", "c"]);
+    assert_eq!(
+        keys(store.scan("ab".to_owned().."b".to_owned()))?,
+        ["ab", "abc"]
+    );
+    assert_eq!(
+        keys(store.scan("ab".to_owned()..="b".to_owned()))?,
+        ["ab", "abc", "b"]
+    );
+    assert_eq!(
+        keys(store.scan("c".to_owned().."a".to_owned()))?,
+        Vec::<String>::new()
+    );
+    assert_eq!(keys(store.prefix("ab".to_owned()))?, ["ab", "abc"]);
+    assert_eq!(keys(store.prefix("d".to_owned()))?, Vec::<String>::new());
+
+    let pairs: Vec<_> = store.prefix("abc".to_owned()).collect::<Result<_>>()?;
+    assert_eq!(pairs, [("abc".to_owned(), "abc_value".to_owned())]);
+    Ok(())
+}
+
+#[test]
+fn scan_and_prefix_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_and_prefix(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn scan_and_prefix_memory() -> Result<()> {
+    scan_and_prefix(MemStore::new())
+}
+
+// Pairs are read as they're reached: writes ahead of a scan show up in it.
+#[test]
+fn scan_is_lazy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let mut pairs = store.scan(..);
+    assert_eq!(
+        pairs.next().unwrap()?,
+        ("key1".to_owned(), "value1".to_owned())
+    );
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "new3".to_owned())?;
+    assert_eq!(
+        pairs.next().unwrap()?,
+        ("key2".to_owned(), "value2".to_owned())
+    );
+    assert_eq!(
+        pairs.next().unwrap()?,
+        ("key3".to_owned(), "new3".to_owned())
+    );
+    assert!(pairs.next().is_none());
+    Ok(())
+}
+
+// A compaction in the middle of a scan moves the records it hasn't read yet.
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:0>3}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut pairs = store.scan(..);
+    for key_id in 0..50 {
+        assert_eq!(pairs.next().unwrap()?.0, format!("key{:0>3}", key_id));
+    }
+    store.compact()?;
+    for key_id in 50..100 {
+        let (key, val) = pairs.next().unwrap()?;
+        assert_eq!(key, format!("key{:0>3}", key_id));
+        assert_eq!(val, format!("value{}", key_id));
+    }
+    assert!(pairs.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn cli_scan_and_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    for key in ["b", "a", "ab", "c"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, &format!("{}_value", key)])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a\ta_value\nab\tab_value\nb\tb_value\nc\tc_value\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "ab", "c"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("ab\tab_value\nb\tb_value\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["keys"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a\nab\nb\nc\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["keys", "a"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("a\nab\n"));
+}
+
+// Logs from before the engine was recorded are the kvs engine's, and the memory
+// engine records nothing.
+#[test]
+fn cli_engine_of_unrecorded_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("written by the kvs engine"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(WalkDir::new(temp_dir.path()).into_iter().count(), 1);
+    Ok(())
+}